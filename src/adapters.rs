@@ -1,10 +1,89 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
-use crate::{set::Set, Card, RawMethod};
+use crate::{
+    seconds_since_epoch,
+    set::{SaveOptions, Set, SetSources, SET_VERSION},
+    Card, RawMethod,
+};
 use anyhow::{anyhow, Context, Result};
-use rhai::{Dynamic, Engine, Scope};
+use rhai::{Dynamic, Engine, Map, Scope};
 use uuid::Uuid;
 
+/// The ways in which a single card can be affected by updating a set from its source.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UpdateStatus {
+    /// The card did not exist in the set before, and it has been added.
+    Added,
+    /// The card's answer changed in the source, so its learning progress has been reset for all methods. Whether or not
+    /// it was starred, difficult, or seen in the current test is preserved.
+    Changed,
+    /// The card's question changed in the source, but the adapter identified it as the same card as before, so its
+    /// question has been updated and all its progress has been kept.
+    Reworded,
+    /// The card was identical in the source, and all its progress has been kept.
+    Unchanged,
+    /// The card is no longer produced by the adapter. What happens to it is determined by the [`RemovalPolicy`]
+    /// the update was run with.
+    Removed,
+}
+
+/// What should happen to cards in a set that the adapter no longer produces from the source when the set is
+/// updated.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RemovalPolicy {
+    /// Keep the cards in the set as they are. They will still be reviewed in learn sessions and tests.
+    #[default]
+    Keep,
+    /// Move the cards into the set's archive, where they will be kept with all their progress, but will no longer
+    /// be reviewed or listed. If they come back into the source in a later update, they'll be moved back into the set.
+    Archive,
+    /// Delete the cards from the set entirely. This is irreversible!
+    Delete,
+}
+
+/// A record of what happened to a single card during an update.
+#[derive(Clone, Debug)]
+pub struct CardUpdate {
+    /// The unique identifier of the card in the set.
+    pub id: Uuid,
+    /// The question on the card, as it appears in the source.
+    pub question: String,
+    /// The answer on the card, as it appears in the source. For removed cards, this will be the answer they had
+    /// in the set.
+    pub answer: String,
+    /// If the card's answer has changed, this will be the answer it had before the update.
+    pub previous_answer: Option<String>,
+    /// If the card's question has changed, this will be the question it had before the update.
+    pub previous_question: Option<String>,
+    /// The stable identifier the adapter gave this card, if it gave one.
+    pub source_id: Option<String>,
+    /// What the update did to this card.
+    pub status: UpdateStatus,
+    /// Whether or not the card was in the set's archive, and has been moved back into the set because it's in the source
+    /// again. Its progress will be handled as for any other card with the same status.
+    pub unarchived: bool,
+}
+
+/// A report of what an update did to each of the cards the adapter produced, in the order the adapter
+/// produced them, followed by any cards that were no longer produced.
+#[derive(Clone, Debug, Default)]
+pub struct UpdateReport {
+    /// The individual changes made to each card.
+    pub cards: Vec<CardUpdate>,
+    /// Any questions (or identifiers, for adapters that provide them) that the adapter produced more than once. Only
+    /// the first card with each of these will have been used.
+    pub duplicates: Vec<String>,
+}
+impl UpdateReport {
+    /// Gets the number of cards in this report with the given status.
+    pub fn count(&self, status: UpdateStatus) -> usize {
+        self.cards
+            .iter()
+            .filter(|card| card.status == status)
+            .count()
+    }
+}
+
 impl Set {
     /// Creates a new [`Set`] from the given source using the given Rhai script. The script is required
     /// to assemble a Rhai array of question/answer tuples, and Forn will do the rest of the work
@@ -20,22 +99,34 @@ impl Set {
     ) -> Result<Self> {
         // Create an empty set and then populate it
         let mut set = Self {
-            method: match &method {
-                RawMethod::Inbuilt(name) => name,
-                RawMethod::Custom { name, .. } => name,
-            }
-            .to_string(),
+            version: SET_VERSION,
+            method: method.name().to_string(),
             cards: HashMap::new(),
+            archived: HashMap::new(),
+            save_options: SaveOptions::default(),
+            sources: SetSources::default(),
+            last_updated: None,
+            exam_date: None,
+            method_parameters: BTreeMap::new(),
+            method_fingerprints: BTreeMap::new(),
             run_state: None,
             test_in_progress: false,
         };
-        set.update_with_adapter(script, src, method, engine)?;
+        set.update_with_adapter(script, src, method, RemovalPolicy::Keep, engine)?;
 
         Ok(set)
     }
-    /// Updates this set from the given source. This will add any new question/answer pairs the adapter script finds,
-    /// and will update any answers that change. If a question changes, it will be registered as a new card. Any cards
-    /// whose answers change will have their metadata reset in order to allow the user to learn the new card.
+    /// Updates this set from the given source. This will add any new cards the adapter script finds, and cards whose
+    /// answers have changed will have their progress reset so the user can learn them again. Cards are matched by the
+    /// identifiers the adapter gives them, if it gives any, and otherwise by their questions, so a card whose question
+    /// changes will only keep its progress (as a reworded card) if the adapter gives it an identifier: otherwise, it
+    /// will be registered as a new card. Cards that have only been reworded, or are unchanged, will keep all their
+    /// progress, as will archived cards that come back into the source, which will be restored. Cards that the adapter
+    /// no longer produces will be kept, archived, or deleted according to the given [`RemovalPolicy`]. If the source
+    /// contains the same card more than once, only the first instance will be used.
+    ///
+    /// This returns a report of what happened to each card the adapter produced, which will be exactly what
+    /// [`Self::diff_with_adapter`] would have returned before the update.
     ///
     /// The arguments provided to this function must satisfy the same requirements as those provided to
     /// [`Self::new_with_adapter`].
@@ -44,49 +135,638 @@ impl Set {
         script: &str,
         src: String,
         method: RawMethod,
+        policy: RemovalPolicy,
+        engine: &Engine,
+    ) -> Result<UpdateReport> {
+        let parameters = self.method_parameters.get(method.name());
+        let method = method.into_method(parameters, engine)?;
+        // Updating only gives new cards the method's default data, so it doesn't matter if a custom method's script has
+        // changed (that's checked when the set is next learned), but a new set should remember the script it was made with
+        if !self.method_fingerprints.contains_key(&method.name) {
+            self.record_method(&method);
+        }
+        let adapter_cards = run_adapter(script, src, engine)?;
+        let (plan, duplicates) = self.plan_update(adapter_cards);
+
+        let mut report = UpdateReport {
+            cards: Vec::new(),
+            duplicates,
+        };
+        for (update, adapter_card) in plan {
+            match (update.status, adapter_card) {
+                (UpdateStatus::Added, Some(adapter_card)) => {
+                    let mut card = Card {
+                        question: String::new(),
+                        answer: String::new(),
+                        source_id: None,
+                        tags: Vec::new(),
+                        hint: None,
+                        source_line: None,
+                        extra: BTreeMap::new(),
+                        source_position: None,
+                        seen_in_test: false,
+                        difficult: false,
+                        starred: false,
+                        method_states: BTreeMap::from([(
+                            method.name.clone(),
+                            (method.get_default_metadata)()?,
+                        )]),
+                        history: Vec::new(),
+                    };
+                    adapter_card.apply_to(&mut card);
+                    self.cards.insert(update.id, card);
+                }
+                (UpdateStatus::Changed, Some(adapter_card)) => {
+                    if update.unarchived {
+                        self.unarchive(update.id);
+                    }
+                    // The user will need to learn the new answer from scratch
+                    let card = self.cards.get_mut(&update.id).unwrap();
+                    adapter_card.apply_to(card);
+                    card.method_states =
+                        BTreeMap::from([(method.name.clone(), (method.get_default_metadata)()?)]);
+                }
+                (UpdateStatus::Reworded | UpdateStatus::Unchanged, Some(adapter_card)) => {
+                    if update.unarchived {
+                        self.unarchive(update.id);
+                    }
+                    // Even if the question and answer are the same, other properties might have changed
+                    let card = self.cards.get_mut(&update.id).unwrap();
+                    adapter_card.apply_to(card);
+                }
+                (UpdateStatus::Removed, None) => match policy {
+                    RemovalPolicy::Keep => {}
+                    RemovalPolicy::Archive => {
+                        let card = self.cards.remove(&update.id).unwrap();
+                        self.archived.insert(update.id, card);
+                    }
+                    RemovalPolicy::Delete => {
+                        self.cards.remove(&update.id);
+                    }
+                },
+                _ => unreachable!("removed cards should have no adapter card, and vice versa"),
+            }
+            report.cards.push(update);
+        }
+        self.last_updated = Some(seconds_since_epoch());
+
+        Ok(report)
+    }
+    /// Moves the archived card with the given identifier back into the set, keeping all its progress.
+    fn unarchive(&mut self, id: Uuid) {
+        let card = self.archived.remove(&id).unwrap();
+        self.cards.insert(id, card);
+    }
+    /// Works out what [`Self::update_with_adapter`] would do to this set with the given source, without actually
+    /// changing anything. This is useful for previewing which cards will have their progress reset by an update.
+    ///
+    /// Any new cards in the returned report will be assigned fresh identifiers, which have no meaning outside the
+    /// report.
+    pub(crate) fn diff_with_adapter(
+        &self,
+        script: &str,
+        src: String,
         engine: &Engine,
-    ) -> Result<()> {
-        let method = method.into_method(engine)?;
-
-        let mut scope = Scope::new();
-        scope.push_constant("SOURCE", src);
-        // This will get *all* the cards in the source, which we will then compare
-        // with what we already have
-        let raw_array: Vec<Dynamic> = engine
-            .eval_with_scope(&mut scope, script)
-            .with_context(|| "failed to run adapter script")?;
-
-        for dyn_elem in raw_array {
-            let elems: Vec<String> = dyn_elem
-                .into_typed_array()
-                .map_err(|_| anyhow!("couldn't parse adapter results"))?;
-
-            let new_card = Card {
+    ) -> Result<UpdateReport> {
+        let adapter_cards = run_adapter(script, src, engine)?;
+        let (plan, duplicates) = self.plan_update(adapter_cards);
+
+        Ok(UpdateReport {
+            cards: plan.into_iter().map(|(update, _)| update).collect(),
+            duplicates,
+        })
+    }
+    /// Works out what should happen to each of the given cards from an adapter, and to each of the cards already in this
+    /// set, in an update. Each planned change is returned with the adapter card that should be applied to the set, which
+    /// will be `None` only for removed cards. Any duplicates found will be returned separately.
+    fn plan_update(
+        &self,
+        adapter_cards: Vec<AdapterCard>,
+    ) -> (Vec<(CardUpdate, Option<AdapterCard>)>, Vec<String>) {
+        // Archived cards are indexed too, so cards that come back into the source get their progress back
+        let index = CardIndex::new(self);
+
+        let mut plan: Vec<(CardUpdate, Option<AdapterCard>)> = Vec::new();
+        let mut claimed = HashSet::new();
+        let mut seen_keys = HashSet::new();
+        let mut duplicates = Vec::new();
+        let mut reported_duplicates = HashSet::new();
+        for adapter_card in adapter_cards {
+            // Only the first instance of a card counts, the rest will be ignored (identifiers and questions are kept
+            // apart, so a card with the identifier `3` doesn't clash with a card without one whose question is `3`)
+            let key = match &adapter_card.id {
+                Some(id) => (true, id.clone()),
+                None => (false, adapter_card.question.clone()),
+            };
+            if !seen_keys.insert(key.clone()) {
+                if reported_duplicates.insert(key.clone()) {
+                    duplicates.push(key.1);
+                }
+                continue;
+            }
+
+            let found = index.find(adapter_card.id.as_deref(), &adapter_card.question, &claimed);
+            let update = if let Some((id, card)) = found {
+                let previous_question =
+                    (card.question != adapter_card.question).then(|| card.question.clone());
+                let previous_answer =
+                    (card.answer != adapter_card.answer).then(|| card.answer.clone());
+                let status = if previous_answer.is_some() {
+                    UpdateStatus::Changed
+                } else if previous_question.is_some() {
+                    UpdateStatus::Reworded
+                } else {
+                    UpdateStatus::Unchanged
+                };
+                CardUpdate {
+                    id: *id,
+                    question: adapter_card.question.clone(),
+                    answer: adapter_card.answer.clone(),
+                    previous_answer,
+                    previous_question,
+                    source_id: adapter_card.id.clone(),
+                    status,
+                    unarchived: self.archived.contains_key(id),
+                }
+            } else {
+                CardUpdate {
+                    id: Uuid::new_v4(),
+                    question: adapter_card.question.clone(),
+                    answer: adapter_card.answer.clone(),
+                    previous_answer: None,
+                    previous_question: None,
+                    source_id: adapter_card.id.clone(),
+                    status: UpdateStatus::Added,
+                    unarchived: false,
+                }
+            };
+            claimed.insert(update.id);
+            plan.push((update, Some(adapter_card)));
+        }
+
+        // Anything we haven't seen in the source has been removed from it
+        let removed = self
+            .cards
+            .iter()
+            .filter(|(id, _card)| !claimed.contains(*id))
+            .map(|(id, card)| {
+                let update = CardUpdate {
+                    id: *id,
+                    question: card.question.clone(),
+                    answer: card.answer.clone(),
+                    previous_answer: None,
+                    previous_question: None,
+                    source_id: card.source_id.clone(),
+                    status: UpdateStatus::Removed,
+                    unarchived: false,
+                };
+                (update, None)
+            })
+            .collect::<Vec<_>>();
+        plan.extend(removed);
+
+        (plan, duplicates)
+    }
+}
+
+/// The cards in a set (including archived ones), indexed by how they can be matched up with cards from elsewhere, so we
+/// don't have to search the whole set for every card.
+pub(crate) struct CardIndex<'a> {
+    by_source_id: HashMap<&'a str, Vec<(&'a Uuid, &'a Card)>>,
+    by_question: HashMap<&'a str, Vec<(&'a Uuid, &'a Card)>>,
+}
+impl<'a> CardIndex<'a> {
+    /// Indexes all the cards in the given set, active ones first.
+    pub(crate) fn new(set: &'a Set) -> Self {
+        let mut by_source_id: HashMap<_, Vec<_>> = HashMap::new();
+        let mut by_question: HashMap<_, Vec<_>> = HashMap::new();
+        for (id, card) in set.cards.iter().chain(set.archived.iter()) {
+            if let Some(source_id) = &card.source_id {
+                by_source_id
+                    .entry(source_id.as_str())
+                    .or_default()
+                    .push((id, card));
+            }
+            by_question
+                .entry(card.question.as_str())
+                .or_default()
+                .push((id, card));
+        }
+
+        Self {
+            by_source_id,
+            by_question,
+        }
+    }
+    /// Finds the first card that hasn't already been claimed by another card with the given identifier, if there is one,
+    /// and otherwise by the given question. A card with an identifier will only be matched by question with cards that
+    /// don't have one (which lets adapters start providing identifiers for cards in existing sets), but a card without one
+    /// can be matched with any card that has the same question.
+    pub(crate) fn find(
+        &self,
+        source_id: Option<&str>,
+        question: &str,
+        claimed: &HashSet<Uuid>,
+    ) -> Option<(&'a Uuid, &'a Card)> {
+        match source_id {
+            Some(source_id) => find_unclaimed(self.by_source_id.get(source_id), claimed, |_| true)
+                .or_else(|| {
+                    find_unclaimed(self.by_question.get(question), claimed, |card| {
+                        card.source_id.is_none()
+                    })
+                }),
+            None => find_unclaimed(self.by_question.get(question), claimed, |_| true),
+        }
+    }
+}
+
+/// Finds the first of the given candidate cards from a set that hasn't already been claimed by another card, and which
+/// satisfies the given predicate.
+fn find_unclaimed<'a>(
+    candidates: Option<&Vec<(&'a Uuid, &'a Card)>>,
+    claimed: &HashSet<Uuid>,
+    predicate: impl Fn(&Card) -> bool,
+) -> Option<(&'a Uuid, &'a Card)> {
+    candidates
+        .into_iter()
+        .flatten()
+        .find(|(id, card)| !claimed.contains(*id) && predicate(card))
+        .copied()
+}
+
+/// A card as produced by an adapter script, before it has been integrated into a set.
+struct AdapterCard {
+    question: String,
+    answer: String,
+    /// A stable identifier for this card, which will be used to match it with the cards already in a set, if
+    /// the adapter provided one.
+    id: Option<String>,
+    /// Any tags the adapter gave this card. If this is `None`, any tags the card already has will be left alone.
+    tags: Option<Vec<String>>,
+    hint: Option<String>,
+    source_line: Option<usize>,
+    extra: BTreeMap<String, Dynamic>,
+    /// The index of this card among all those the adapter produced.
+    position: usize,
+}
+impl AdapterCard {
+    /// Overwrites the content of the given card with the content of this adapter card, leaving all progress alone.
+    fn apply_to(self, card: &mut Card) {
+        card.question = self.question;
+        card.answer = self.answer;
+        card.source_id = self.id;
+        if let Some(tags) = self.tags {
+            card.tags = tags;
+        }
+        card.hint = self.hint;
+        card.source_line = self.source_line;
+        card.extra = self.extra;
+        card.source_position = Some(self.position);
+    }
+}
+
+/// Runs the given adapter script on the given source, producing *all* the cards in the source, which can then be compared
+/// with those already in a set.
+///
+/// Adapters may produce each card as either an array of `[question, answer]` or `[question, answer, id]`, or as an object
+/// map with `question` and `answer` properties. Maps may optionally also have `id`, `tags` (an array of strings), `hint`,
+/// and `line` (the line in the source the card came from) properties, and any other properties will be kept as extra
+/// fields on the card.
+fn run_adapter(script: &str, src: String, engine: &Engine) -> Result<Vec<AdapterCard>> {
+    let mut scope = Scope::new();
+    scope.push_constant("SOURCE", src);
+    let raw_array: Vec<Dynamic> = engine
+        .eval_with_scope(&mut scope, script)
+        .with_context(|| "failed to run adapter script")?;
+
+    let mut cards = Vec::new();
+    for (position, dyn_elem) in raw_array.into_iter().enumerate() {
+        let card = if dyn_elem.is_map() {
+            let mut map = dyn_elem.cast::<Map>();
+            AdapterCard {
+                question: map
+                    .remove("question")
+                    .ok_or_else(|| anyhow!("adapter did not return question for card"))?
+                    .into_string()
+                    .map_err(|_| anyhow!("adapter returned non-string question for card"))?,
+                answer: map
+                    .remove("answer")
+                    .ok_or_else(|| anyhow!("adapter did not return answer for card"))?
+                    .into_string()
+                    .map_err(|_| anyhow!("adapter returned non-string answer for card"))?,
+                id: map.remove("id").map(parse_id).transpose()?,
+                tags: map
+                    .remove("tags")
+                    .map(|tags| {
+                        tags.into_typed_array::<String>().map_err(|_| {
+                            anyhow!("adapter returned invalid tags for card (must be an array of strings)")
+                        })
+                    })
+                    .transpose()?,
+                hint: map
+                    .remove("hint")
+                    .map(|hint| {
+                        hint.into_string()
+                            .map_err(|_| anyhow!("adapter returned non-string hint for card"))
+                    })
+                    .transpose()?,
+                source_line: map
+                    .remove("line")
+                    .map(|line| {
+                        line.as_int()
+                            .ok()
+                            .and_then(|line| usize::try_from(line).ok())
+                            .ok_or_else(|| anyhow!("adapter returned invalid line for card (must be a non-negative integer)"))
+                    })
+                    .transpose()?,
+                // Whatever's left is arbitrary extra data
+                extra: map
+                    .into_iter()
+                    .map(|(key, value)| (key.to_string(), value))
+                    .collect(),
+                position,
+            }
+        } else {
+            let mut elems = dyn_elem
+                .into_array()
+                .map_err(|_| anyhow!("couldn't parse adapter results"))?
+                .into_iter();
+            AdapterCard {
                 question: elems
-                    .get(0)
+                    .next()
                     .ok_or_else(|| anyhow!("adapter did not return question for card"))?
-                    .to_string(),
+                    .into_string()
+                    .map_err(|_| anyhow!("adapter returned non-string question for card"))?,
                 answer: elems
-                    .get(1)
+                    .next()
                     .ok_or_else(|| anyhow!("adapter did not return answer for card"))?
-                    .to_string(),
-                seen_in_test: false,
-                difficult: false,
-                starred: false,
-                method_data: (method.get_default_metadata)()?,
-            };
-            // If we've already got this question, update the answer if necessary, otherwise add it afresh
-            let found = self
-                .cards
-                .iter_mut()
-                .find(|(_id, card)| card.question == new_card.question);
-            if let Some((_id, card)) = found {
-                *card = new_card;
-            } else {
-                self.cards.insert(Uuid::new_v4(), new_card);
+                    .into_string()
+                    .map_err(|_| anyhow!("adapter returned non-string answer for card"))?,
+                id: elems.next().map(parse_id).transpose()?,
+                tags: None,
+                hint: None,
+                source_line: None,
+                extra: BTreeMap::new(),
+                position,
             }
+        };
+
+        cards.push(card);
+    }
+
+    Ok(cards)
+}
+
+/// Parses a card identifier provided by an adapter, which may be either a string or an integer (e.g. a line number).
+fn parse_id(id: Dynamic) -> Result<String> {
+    if id.is_string() || id.is_int() {
+        Ok(id.to_string())
+    } else {
+        Err(anyhow!(
+            "adapter returned invalid id for card (must be a string or integer)"
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        set::tests::{card, set},
+        Forne,
+    };
+
+    /// An adapter that produces a card from each line of the source, as `question|answer` or `question|answer|id`.
+    const ADAPTER: &str = r#"SOURCE.split("\n").map(|line| line.split("|"))"#;
+
+    fn new_set(src: &str) -> Set {
+        Set::new_with_adapter(
+            src.to_string(),
+            ADAPTER,
+            RawMethod::Inbuilt("speed-v1".to_string()),
+            &Forne::create_engine(),
+        )
+        .unwrap()
+    }
+
+    fn update(set: &mut Set, src: &str, policy: RemovalPolicy) -> UpdateReport {
+        set.update_with_adapter(
+            ADAPTER,
+            src.to_string(),
+            RawMethod::Inbuilt("speed-v1".to_string()),
+            policy,
+            &Forne::create_engine(),
+        )
+        .unwrap()
+    }
+
+    /// Gets the identifier of the card in the given set (active or archived) with the given question.
+    fn id_of(set: &Set, question: &str) -> Uuid {
+        set.cards
+            .iter()
+            .chain(set.archived.iter())
+            .find(|(_, card)| card.question == question)
+            .map(|(id, _)| *id)
+            .unwrap()
+    }
+
+    /// Marks the card with the given question as though it had been learned a little.
+    fn make_progress(set: &mut Set, question: &str) {
+        let id = id_of(set, question);
+        let card = set.cards.get_mut(&id).unwrap();
+        card.starred = true;
+        card.method_states
+            .insert("speed-v1".to_string(), Dynamic::from_int(42));
+    }
+
+    fn has_progress(card: &Card) -> bool {
+        card.method_states["speed-v1"].as_int().ok() == Some(42)
+    }
+
+    fn adapter_card(question: &str, answer: &str, id: Option<&str>) -> AdapterCard {
+        AdapterCard {
+            question: question.to_string(),
+            answer: answer.to_string(),
+            id: id.map(str::to_string),
+            tags: None,
+            hint: None,
+            source_line: None,
+            extra: BTreeMap::new(),
+            position: 0,
+        }
+    }
+
+    /// Plans an update of the given set with the given adapter cards, returning the status of each planned change.
+    fn statuses(set: &Set, adapter_cards: Vec<AdapterCard>) -> Vec<(Uuid, UpdateStatus)> {
+        let (plan, _) = set.plan_update(adapter_cards);
+        plan.into_iter()
+            .map(|(update, _)| (update.id, update.status))
+            .collect()
+    }
+
+    #[test]
+    fn cards_without_identifiers_match_by_question_even_if_the_set_has_identifiers() {
+        let id = Uuid::new_v4();
+        let mut with_id = card("Q", "A");
+        with_id.source_id = Some("a".to_string());
+        let set = set([(id, with_id)]);
+
+        assert_eq!(
+            statuses(&set, vec![adapter_card("Q", "A", None)]),
+            [(id, UpdateStatus::Unchanged)]
+        );
+    }
+
+    #[test]
+    fn cards_with_identifiers_dont_take_over_cards_with_other_identifiers() {
+        let id = Uuid::new_v4();
+        let mut with_id = card("Q", "A");
+        with_id.source_id = Some("a".to_string());
+        let set = set([(id, with_id)]);
+
+        let statuses = statuses(&set, vec![adapter_card("Q", "A", Some("b"))]);
+        assert_eq!(statuses.len(), 2);
+        assert_ne!(statuses[0].0, id);
+        assert_eq!(statuses[0].1, UpdateStatus::Added);
+        assert_eq!(statuses[1], (id, UpdateStatus::Removed));
+    }
+
+    #[test]
+    fn unchanged_cards_keep_their_progress() {
+        let mut set = new_set("Q1|A1\nQ2|A2");
+        make_progress(&mut set, "Q1");
+
+        let report = update(&mut set, "Q1|A1\nQ2|A2", RemovalPolicy::Keep);
+
+        assert_eq!(report.count(UpdateStatus::Unchanged), 2);
+        assert_eq!(set.cards.len(), 2);
+        assert!(has_progress(&set.cards[&id_of(&set, "Q1")]));
+    }
+
+    #[test]
+    fn changed_answers_reset_progress() {
+        let mut set = new_set("Q1|A1\nQ2|A2");
+        make_progress(&mut set, "Q1");
+
+        let report = update(&mut set, "Q1|new\nQ2|A2\nQ3|A3", RemovalPolicy::Keep);
+
+        assert_eq!(report.count(UpdateStatus::Changed), 1);
+        assert_eq!(report.count(UpdateStatus::Added), 1);
+        assert_eq!(report.cards[0].previous_answer.as_deref(), Some("A1"));
+        let card = &set.cards[&id_of(&set, "Q1")];
+        assert_eq!(card.answer, "new");
+        assert!(!has_progress(card));
+        // Stars are kept, since they're up to the user
+        assert!(card.starred);
+    }
+
+    #[test]
+    fn removed_cards_are_handled_according_to_the_policy() {
+        for (policy, active, archived) in [
+            (RemovalPolicy::Keep, 2, 0),
+            (RemovalPolicy::Archive, 1, 1),
+            (RemovalPolicy::Delete, 1, 0),
+        ] {
+            let mut set = new_set("Q1|A1\nQ2|A2");
+
+            let report = update(&mut set, "Q1|A1", policy);
+
+            assert_eq!(report.count(UpdateStatus::Removed), 1);
+            assert_eq!(report.cards[1].question, "Q2");
+            assert_eq!(set.cards.len(), active);
+            assert_eq!(set.archived.len(), archived);
         }
+    }
+
+    #[test]
+    fn archived_cards_are_restored_with_their_progress() {
+        let mut set = new_set("Q1|A1\nQ2|A2");
+        make_progress(&mut set, "Q2");
+        update(&mut set, "Q1|A1", RemovalPolicy::Archive);
+
+        let report = update(&mut set, "Q1|A1\nQ2|A2", RemovalPolicy::Archive);
+
+        assert!(report.cards[1].unarchived);
+        assert_eq!(report.cards[1].status, UpdateStatus::Unchanged);
+        assert!(set.archived.is_empty());
+        assert!(has_progress(&set.cards[&id_of(&set, "Q2")]));
+    }
+
+    #[test]
+    fn diffs_match_updates_without_changing_the_set() {
+        let mut set = new_set("Q1|A1\nQ2|A2\nQ3|A3");
+        let src = "Q1|A1\nQ2|new\nQ4|A4";
+
+        let diff = set
+            .diff_with_adapter(ADAPTER, src.to_string(), &Forne::create_engine())
+            .unwrap();
+        assert_eq!(set.cards.len(), 3);
+        assert_eq!(set.cards[&id_of(&set, "Q2")].answer, "A2");
+        let report = update(&mut set, src, RemovalPolicy::Keep);
+
+        let summarize = |report: &UpdateReport| {
+            report
+                .cards
+                .iter()
+                .map(|card| (card.question.clone(), card.status))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(summarize(&diff), summarize(&report));
+        assert_eq!(
+            summarize(&report),
+            [
+                ("Q1".to_string(), UpdateStatus::Unchanged),
+                ("Q2".to_string(), UpdateStatus::Changed),
+                ("Q4".to_string(), UpdateStatus::Added),
+                ("Q3".to_string(), UpdateStatus::Removed),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicates_are_reported_once() {
+        let set = new_set("Q1|A1");
+
+        let (plan, duplicates) = set.plan_update(vec![
+            adapter_card("Q1", "A1", None),
+            adapter_card("Q1", "A2", None),
+            adapter_card("Q1", "A3", None),
+        ]);
+
+        assert_eq!(duplicates, ["Q1"]);
+        assert_eq!(plan.len(), 1);
+        assert_eq!(plan[0].0.status, UpdateStatus::Unchanged);
+    }
+
+    #[test]
+    fn reworded_cards_with_identifiers_keep_their_progress() {
+        let mut set = new_set("Q1|A1|1\nQ2|A2|2");
+        make_progress(&mut set, "Q1");
+        let id = id_of(&set, "Q1");
+
+        let report = update(&mut set, "New Q1|A1|1\nQ2|A2|2", RemovalPolicy::Keep);
+
+        assert_eq!(report.cards[0].status, UpdateStatus::Reworded);
+        assert_eq!(report.cards[0].previous_question.as_deref(), Some("Q1"));
+        assert_eq!(set.cards.len(), 2);
+        assert_eq!(set.cards[&id].question, "New Q1");
+        assert!(has_progress(&set.cards[&id]));
+    }
+
+    #[test]
+    fn identifiers_and_questions_dont_clash() {
+        let set = set([]);
+
+        let (plan, duplicates) = set.plan_update(vec![
+            adapter_card("Q", "A", Some("3")),
+            adapter_card("3", "A", None),
+            adapter_card("Other Q", "A", Some("3")),
+        ]);
 
-        Ok(())
+        assert_eq!(duplicates, ["3"]);
+        assert_eq!(plan.len(), 2);
+        assert!(plan
+            .iter()
+            .all(|(update, _)| update.status == UpdateStatus::Added));
     }
 }
@@ -6,8 +6,11 @@ compile_error!("the cli binary must be built with the `cli` feature flag");
 fn main() -> anyhow::Result<()> {
     use anyhow::Context;
     use clap::Parser;
-    use forne::{Forne, Set};
-    use opts::{Args, Command};
+    use forne::{
+        ConflictKind, Forne, ProgressKey, Query, RemovalPolicy, SaveOptions, Set, SetFormat,
+        SetSources,
+    };
+    use opts::{Args, Command, LockArgs};
     use std::fs;
     use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
@@ -18,16 +21,45 @@ fn main() -> anyhow::Result<()> {
             output,
             adapter,
             method,
+            progress,
+            embed_adapter,
+            exam_date,
+            lock: LockArgs { force },
         } => {
-            let contents =
-                fs::read_to_string(input).with_context(|| "failed to read from source file")?;
+            let _lock = lock_set(&output, force)?;
+            let mut sources = SetSources::default();
+            let contents = resolve_source(&output, Some(input), &mut sources)?;
             let adapter_script =
-                fs::read_to_string(adapter).with_context(|| "failed to read adapter script")?;
-            let method = method_from_string(method)?;
+                resolve_adapter(&output, Some(adapter), embed_adapter, &mut sources)?;
+            let raw_method = method_from_string(method.clone())?;
+            record_method(&output, &method, &raw_method, &mut sources);
 
-            let forne = Forne::new_set(contents, &adapter_script, method)?;
-            let json = forne.save_set()?;
-            fs::write(output, json).with_context(|| "failed to write new set to output file")?;
+            let mut forne = Forne::new_set(contents, &adapter_script, raw_method)?;
+            forne.set_sources(sources);
+            if let Some(progress) = progress {
+                let progress = fs::read_to_string(progress)
+                    .with_context(|| "failed to read from progress file")?;
+                let report = forne.apply_progress(&progress)?;
+                for key in report.duplicates {
+                    match key {
+                        ProgressKey::SourceId(source_id) => println!(
+                            "! More than one card has the identifier {}, so its progress wasn't applied.",
+                            source_id
+                        ),
+                        ProgressKey::Question(question) => println!(
+                            "! More than one card has the question {}, so its progress wasn't applied.",
+                            question
+                        ),
+                    }
+                }
+                println!("Progress applied to {} card(s).", report.applied);
+            }
+            // An exam date given explicitly takes precedence over any in the progress
+            if exam_date.is_some() {
+                forne.set_exam_date(exam_date);
+            }
+            let bytes = forne.save_set_as(set_format(&output, args.format))?;
+            write_set(&output, &bytes).with_context(|| "failed to write new set to output file")?;
 
             println!("New set created!");
         }
@@ -36,36 +68,81 @@ fn main() -> anyhow::Result<()> {
             source,
             adapter,
             method,
+            prune,
+            archive,
+            dry_run,
+            lock: LockArgs { force },
+            embed_adapter,
         } => {
-            let json =
-                fs::read_to_string(&set_file).with_context(|| "failed to read from set file")?;
-            let set = Set::from_json(&json)?;
-            let source =
-                fs::read_to_string(source).with_context(|| "failed to read from source file")?;
+            let lock = lock_set(&set_file, force)?;
+            let mut set = recover_set(&set_file, args.format, &lock)?;
+            let source = resolve_source(&set_file, source, &mut set.sources)?;
             let adapter_script =
-                fs::read_to_string(adapter).with_context(|| "failed to read adapter script")?;
-            let method = method_from_string(method)?;
+                resolve_adapter(&set_file, adapter, embed_adapter, &mut set.sources)?;
+            let method = resolve_method(&set_file, method, &mut set)?;
 
             let mut forne = Forne::from_set(set);
-            forne.update(source, &adapter_script, method)?;
-            let new_json = forne.save_set()?;
-            fs::write(set_file, new_json)
+            if dry_run {
+                let report = forne.diff(source, &adapter_script)?;
+                print_report(&report)?;
+                return Ok(());
+            }
+            let policy = if prune {
+                RemovalPolicy::Delete
+            } else if archive {
+                RemovalPolicy::Archive
+            } else {
+                RemovalPolicy::Keep
+            };
+            let report = forne.update(source, &adapter_script, method, policy)?;
+            let bytes = forne.save_set_as(set_format(&set_file, args.format))?;
+            backup_set(&set_file, args.format, args.backups)?;
+            write_set(&set_file, &bytes)
                 .with_context(|| "failed to write updated set to output file")?;
 
-            println!("Set updated successfully!");
+            print_update_summary(&report, policy);
+        }
+        Command::Watch {
+            set: set_file,
+            archive,
+            lock: LockArgs { force },
+        } => {
+            let policy = if archive {
+                RemovalPolicy::Archive
+            } else {
+                RemovalPolicy::Keep
+            };
+            watch(&set_file, policy, force, args.format, args.backups)?;
         }
         Command::Learn {
             set: set_file,
             method,
             ty,
+            query,
             count,
             reset,
+            journal,
+            lock: LockArgs { force },
+            exam_date,
+            params,
+            accept_changes,
         } => {
-            let json =
-                fs::read_to_string(&set_file).with_context(|| "failed to read from set file")?;
-            let set = Set::from_json(&json)?;
+            let lock = lock_set(&set_file, force)?;
+            let mut set = recover_set(&set_file, args.format, &lock)?;
+            let method = resolve_method(&set_file, method, &mut set)?;
+            if exam_date.is_some() {
+                set.exam_date = exam_date;
+            }
+            if !params.is_empty() {
+                set.method_parameters
+                    .entry(method.name().to_string())
+                    .or_default()
+                    .extend(params);
+            }
             let mut forne = Forne::from_set(set);
-            let method = method_from_string(method)?;
+            if accept_changes {
+                forne.accept_method_changes(method.clone())?;
+            }
             if reset && confirm("Are you absolutely certain you want to reset your learn progress? This action is IRREVERSIBLE!!!")? {
                 forne.reset_learn(method.clone())?;
             } else {
@@ -73,11 +150,20 @@ fn main() -> anyhow::Result<()> {
             }
             let mut driver = forne.learn(method)?;
             driver.set_target(ty);
+            if let Some(query) = query {
+                driver.set_query(query);
+            }
             if let Some(count) = count {
                 driver.set_max_count(count);
             }
 
-            let num_reviewed = drive(driver, &set_file)?;
+            let num_reviewed = drive(
+                driver,
+                &set_file,
+                set_format(&set_file, args.format),
+                journal,
+                args.backups,
+            )?;
             println!(
                 "\nLearn session complete! You reviewed {} card(s).",
                 num_reviewed
@@ -89,12 +175,14 @@ fn main() -> anyhow::Result<()> {
             no_star,
             no_unstar,
             ty,
+            query,
             count,
             reset,
+            journal,
+            lock: LockArgs { force },
         } => {
-            let json =
-                fs::read_to_string(&set_file).with_context(|| "failed to read from set file")?;
-            let set = Set::from_json(&json)?;
+            let lock = lock_set(&set_file, force)?;
+            let set = recover_set(&set_file, args.format, &lock)?;
             let mut forne = Forne::from_set(set);
             if reset && confirm("Are you sure you want to reset your test progress?")? {
                 forne.reset_test();
@@ -103,6 +191,9 @@ fn main() -> anyhow::Result<()> {
             }
             let mut driver = forne.test();
             driver.set_target(ty);
+            if let Some(query) = query {
+                driver.set_query(query);
+            }
             if let Some(count) = count {
                 driver.set_max_count(count);
             }
@@ -114,12 +205,220 @@ fn main() -> anyhow::Result<()> {
                 driver.no_mark_unstarred();
             }
 
-            let num_reviewed = drive(driver, &set_file)?;
+            let num_reviewed = drive(
+                driver,
+                &set_file,
+                set_format(&set_file, args.format),
+                journal,
+                args.backups,
+            )?;
             println!("\nTest complete! You reviewed {} card(s).", num_reviewed);
         }
-        Command::List { set, ty } => {
-            let json = fs::read_to_string(set).with_context(|| "failed to read from set file")?;
-            let set = Set::from_json(&json)?;
+        Command::Migrate {
+            set: set_file,
+            method,
+            from,
+            lock: LockArgs { force },
+        } => {
+            let lock = lock_set(&set_file, force)?;
+            let set = recover_set(&set_file, args.format, &lock)?;
+            let from = from.unwrap_or_else(|| set.method.clone());
+            let mut forne = Forne::from_set(set);
+            let method = method_from_string(method)?;
+            if !confirm(&format!("Are you sure you want to migrate your progress from '{}'? Any progress you've made with the new method will be overwritten!", from))? {
+                return Ok(());
+            }
+
+            let num_transformed = forne.migrate(&from, method)?;
+            let bytes = forne.save_set_as(set_format(&set_file, args.format))?;
+            backup_set(&set_file, args.format, args.backups)?;
+            write_set(&set_file, &bytes).with_context(|| "failed to write migrated set")?;
+            println!(
+                "Progress migrated successfully! {} card(s) had their progress transformed.",
+                num_transformed
+            );
+        }
+        Command::Merge {
+            set: set_file,
+            other,
+            lock: LockArgs { force },
+        } => {
+            let lock = lock_set(&set_file, force)?;
+            let mut set = recover_set(&set_file, args.format, &lock)?;
+            // The other set is only read, so any journal it has can only be replayed in memory
+            let other = read_set(&other, args.format)?;
+
+            let report = set.merge(other);
+            let bytes = set.save_as(set_format(&set_file, args.format))?;
+            backup_set(&set_file, args.format, args.backups)?;
+            write_set(&set_file, &bytes).with_context(|| "failed to write merged set")?;
+
+            let mut yellow = ColorSpec::new();
+            yellow.set_fg(Some(Color::Yellow));
+            let mut stdout = StandardStream::stdout(ColorChoice::Always);
+            stdout.set_color(&yellow)?;
+            for conflict in report.conflicts.iter() {
+                match conflict.kind {
+                    ConflictKind::Content { kept_other: false } => println!(
+                        "! {} (question or answer differs, kept this set's version)",
+                        conflict.question
+                    ),
+                    ConflictKind::Content { kept_other: true } => println!(
+                        "! {} (question or answer differs, kept the other set's more recently updated version)",
+                        conflict.question
+                    ),
+                    ConflictKind::DivergedHistory => println!(
+                        "! {} (reviewed in both sets, kept the most recent progress)",
+                        conflict.question
+                    ),
+                }
+            }
+            stdout.reset()?;
+            println!(
+                "Sets merged successfully! {} card(s) added, {} updated with newer progress, {} conflict(s).",
+                report.added,
+                report.updated,
+                report.conflicts.len()
+            );
+        }
+        Command::Restore {
+            set: set_file,
+            backup,
+            list,
+            lock: LockArgs { force },
+        } => {
+            let backups = list_backups(&set_file)?;
+            if list {
+                for (timestamp, _) in backups.iter() {
+                    println!("{} (taken {})", timestamp, describe_age(*timestamp));
+                }
+                return Ok(());
+            }
+
+            let (timestamp, backup_path) = match backup {
+                Some(backup) => backups
+                    .into_iter()
+                    .find(|(timestamp, _)| timestamp.to_string() == backup)
+                    .with_context(|| "no backup with the given name exists for this set")?,
+                None => backups
+                    .into_iter()
+                    .next()
+                    .with_context(|| "there are no backups of this set")?,
+            };
+            let _lock = lock_set(&set_file, force)?;
+            if !confirm(&format!("Are you sure you want to restore the backup taken {}? Any progress you've made since then will be lost (but a backup of the current set will be taken first)!", describe_age(timestamp)))? {
+                return Ok(());
+            }
+
+            let bytes = fs::read(backup_path).with_context(|| "failed to read backup")?;
+            // Make sure the backup is actually a valid set before we use it
+            forne::Set::from_bytes(&bytes, set_format(&set_file, args.format))?;
+            backup_set(&set_file, args.format, args.backups)?;
+            write_set(&set_file, &bytes).with_context(|| "failed to write restored set")?;
+            // Any interrupted session journal belongs to the set we just replaced
+            let journal_file = journal_path(&set_file);
+            if std::path::Path::new(&journal_file).exists() {
+                fs::remove_file(journal_file)
+                    .with_context(|| "failed to remove session journal")?;
+            }
+
+            println!("Set restored!");
+        }
+        Command::Split {
+            set: set_file,
+            content,
+            progress,
+        } => {
+            let mut set = read_set(&set_file, args.format)?;
+            // The sources are kept with the progress, so they should be relative to it
+            move_sources(&mut set.sources, &set_file, &progress);
+            let content_json = set.save_content()?;
+            let progress_json = set.save_progress()?;
+            write_set(&content, content_json.as_bytes())
+                .with_context(|| "failed to write set content")?;
+            write_set(&progress, progress_json.as_bytes())
+                .with_context(|| "failed to write set progress")?;
+
+            println!("Set split into content and progress!");
+        }
+        Command::Join {
+            content,
+            progress,
+            output,
+            lock: LockArgs { force },
+        } => {
+            let _lock = lock_set(&output, force)?;
+            let content =
+                fs::read_to_string(content).with_context(|| "failed to read from content file")?;
+            let progress_json = fs::read_to_string(&progress)
+                .with_context(|| "failed to read from progress file")?;
+            let mut set = Set::from_split(&content, &progress_json)?;
+            move_sources(&mut set.sources, &progress, &output);
+            let bytes = set.save_as(set_format(&output, args.format))?;
+            backup_set(&output, args.format, args.backups)?;
+            write_set(&output, &bytes).with_context(|| "failed to write joined set")?;
+
+            println!("Set content and progress joined!");
+        }
+        Command::Convert {
+            input,
+            output,
+            to,
+            lock: LockArgs { force },
+        } => {
+            let _lock = lock_set(&output, force)?;
+            let mut set = read_set(&input, args.format)?;
+            move_sources(&mut set.sources, &input, &output);
+            let format = to.unwrap_or_else(|| SetFormat::from_path(&output));
+            let bytes = set.save_as(format)?;
+            write_set(&output, &bytes).with_context(|| "failed to write converted set")?;
+
+            println!("Set converted!");
+        }
+        Command::Format {
+            set: set_file,
+            pretty,
+            order,
+            lock: LockArgs { force },
+        } => {
+            let lock = lock_set(&set_file, force)?;
+            let mut set = recover_set(&set_file, args.format, &lock)?;
+            set.save_options = SaveOptions { pretty, order };
+
+            let bytes = set.save_as(set_format(&set_file, args.format))?;
+            backup_set(&set_file, args.format, args.backups)?;
+            write_set(&set_file, &bytes).with_context(|| "failed to write reformatted set")?;
+            println!("Set reformatted! It will be saved in this format from now on.");
+        }
+        Command::Tag {
+            set: set_file,
+            question,
+            add,
+            remove,
+            lock: LockArgs { force },
+        } => {
+            let lock = lock_set(&set_file, force)?;
+            let mut set = recover_set(&set_file, args.format, &lock)?;
+            let card = set
+                .cards
+                .values_mut()
+                .find(|card| card.question == question)
+                .with_context(|| "no card with the given question exists in the set")?;
+            card.tags.retain(|tag| !remove.contains(tag));
+            for tag in add {
+                if !card.tags.contains(&tag) {
+                    card.tags.push(tag);
+                }
+            }
+            let tags = card.tags.join(", ");
+
+            let bytes = set.save_as(set_format(&set_file, args.format))?;
+            backup_set(&set_file, args.format, args.backups)?;
+            write_set(&set_file, &bytes).with_context(|| "failed to write updated set")?;
+            println!("Card tags updated! Tags are now: {}", tags);
+        }
+        Command::List { set, ty, query } => {
+            let set = read_set(&set, args.format)?;
 
             let mut yellow = ColorSpec::new();
             yellow.set_fg(Some(Color::Yellow));
@@ -128,7 +427,10 @@ fn main() -> anyhow::Result<()> {
 
             let mut stdout = StandardStream::stdout(ColorChoice::Always);
             let mut num_printed = 0;
-            let list = set.list(ty);
+            let list = match query {
+                Some(query) => set.list_query(&Query::from(ty).and(query)),
+                None => set.list(ty),
+            };
             for card in list.iter() {
                 stdout.set_color(&yellow)?;
                 println!(
@@ -139,6 +441,9 @@ fn main() -> anyhow::Result<()> {
                 stdout.set_color(&green)?;
                 println!("A: {}", card.answer);
                 stdout.reset()?;
+                if !card.tags.is_empty() {
+                    println!("Tags: {}", card.tags.join(", "));
+                }
 
                 num_printed += 1;
                 // Only print the separator if this isn't the last card
@@ -152,6 +457,494 @@ fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Works out the format of the set stored at the given path, using the format given on the command line if there was one,
+/// and otherwise the path's extension.
+#[cfg(feature = "cli")]
+fn set_format(path: &str, format: Option<forne::SetFormat>) -> forne::SetFormat {
+    format.unwrap_or_else(|| forne::SetFormat::from_path(path))
+}
+
+/// Reads the set stored at the given path in the appropriate format (see [`set_format`]). If there's a session journal
+/// next to the set, it will be replayed over the set in memory, but neither the set nor the journal will be touched on
+/// disk, since the journal might belong to a session that's still running. Commands that write to the set should use
+/// [`recover_set`] instead.
+#[cfg(feature = "cli")]
+fn read_set(path: &str, format: Option<forne::SetFormat>) -> anyhow::Result<forne::Set> {
+    read_set_and_journal(path, format).map(|(set, _)| set)
+}
+
+/// Reads the set stored at the given path like [`read_set`], but, if a journaled session was interrupted, this will
+/// also compact its journal into the set on disk straight away (otherwise the journal could be replayed over newer
+/// progress later). This requires the set's lock, since only then can we be sure the session isn't still running.
+#[cfg(feature = "cli")]
+fn recover_set(
+    path: &str,
+    format: Option<forne::SetFormat>,
+    _lock: &SetLock,
+) -> anyhow::Result<forne::Set> {
+    use anyhow::Context;
+    use std::fs;
+
+    let (set, num_replayed) = read_set_and_journal(path, format)?;
+    if let Some(num_replayed) = num_replayed {
+        let bytes = set.save_as(set_format(path, format))?;
+        write_set(path, &bytes).with_context(|| "failed to write recovered set")?;
+        fs::remove_file(journal_path(path)).with_context(|| "failed to remove session journal")?;
+        eprintln!(
+            "Recovered {} card(s) from an interrupted session.",
+            num_replayed
+        );
+    }
+
+    Ok(set)
+}
+
+/// Reads the set stored at the given path, replaying its session journal in memory if there is one. This returns the
+/// number of journal records replayed if there was a journal.
+#[cfg(feature = "cli")]
+fn read_set_and_journal(
+    path: &str,
+    format: Option<forne::SetFormat>,
+) -> anyhow::Result<(forne::Set, Option<usize>)> {
+    use anyhow::Context;
+    use std::fs;
+
+    let bytes = fs::read(path).with_context(|| "failed to read from set file")?;
+    let mut set = forne::Set::from_bytes(&bytes, set_format(path, format))?;
+    let num_replayed = match fs::read_to_string(journal_path(path)) {
+        Ok(journal) => Some(set.replay_journal(&journal)?),
+        Err(_) => None,
+    };
+
+    Ok((set, num_replayed))
+}
+
+/// Writes the given serialized set to the given path atomically, by writing it to a temporary file first and then
+/// renaming that over the original. This means a crash or a full disk can never leave behind a half-written set.
+#[cfg(feature = "cli")]
+fn write_set(path: &str, bytes: &[u8]) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use std::{
+        fs::{self, File},
+        io::Write,
+    };
+
+    let tmp_path = format!("{}.tmp", path);
+    let mut file = File::create(&tmp_path).with_context(|| "failed to create temporary file")?;
+    file.write_all(bytes)
+        .and_then(|_| file.sync_all())
+        .with_context(|| "failed to write to temporary file")?;
+    fs::rename(&tmp_path, path)
+        .with_context(|| "failed to replace set file with temporary file")?;
+
+    Ok(())
+}
+
+/// Gets the path to the directory backups of the set stored at the given path are kept in.
+#[cfg(feature = "cli")]
+fn backups_path(path: &str) -> String {
+    format!("{}.backups", path)
+}
+
+/// Lists the backups of the set stored at the given path, from newest to oldest, along with the times they were taken
+/// (in milliseconds since Unix epoch).
+#[cfg(feature = "cli")]
+fn list_backups(path: &str) -> anyhow::Result<Vec<(u128, std::path::PathBuf)>> {
+    use anyhow::Context;
+    use std::fs;
+
+    let dir = backups_path(path);
+    let entries = match fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        // No backups have been taken yet
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| "failed to read backups directory"),
+    };
+    let mut backups = Vec::new();
+    for entry in entries {
+        let path = entry
+            .with_context(|| "failed to read backups directory")?
+            .path();
+        // Ignore anything that isn't named with a timestamp
+        let timestamp = path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .and_then(|stem| stem.parse::<u128>().ok());
+        if let Some(timestamp) = timestamp {
+            backups.push((timestamp, path));
+        }
+    }
+    backups.sort_by_key(|(timestamp, _)| std::cmp::Reverse(*timestamp));
+
+    Ok(backups)
+}
+
+/// Takes a timestamped backup of the set stored at the given path (if it exists), deleting the oldest backups so that
+/// no more than `count` are kept. If `count` is zero, or the set is identical to its most recent backup (ignoring the
+/// order of its cards), no backup will be taken.
+///
+/// This should be called just before a command first writes to the set, once it's sure it's going to, so that failed
+/// commands don't push useful backups out.
+#[cfg(feature = "cli")]
+fn backup_set(path: &str, format: Option<forne::SetFormat>, count: usize) -> anyhow::Result<()> {
+    use anyhow::Context;
+    use std::{fs, path::Path, time::SystemTime};
+
+    if count == 0 || !Path::new(path).exists() {
+        return Ok(());
+    }
+    let current = fs::read(path).with_context(|| "failed to read set to back it up")?;
+    if let Some((_, newest)) = list_backups(path)?.first() {
+        let newest = fs::read(newest).with_context(|| "failed to read most recent backup")?;
+        if same_set(&current, &newest, set_format(path, format)) {
+            return Ok(());
+        }
+    }
+
+    let dir = backups_path(path);
+    fs::create_dir_all(&dir).with_context(|| "failed to create backups directory")?;
+    let timestamp = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    // Keep the set's extension, so we know what format the backup is in
+    let backup_path = match Path::new(path).extension() {
+        Some(ext) => format!("{}/{}.{}", dir, timestamp, ext.to_string_lossy()),
+        None => format!("{}/{}", dir, timestamp),
+    };
+    fs::write(backup_path, current).with_context(|| "failed to back up set")?;
+
+    for (_, old_backup) in list_backups(path)?.into_iter().skip(count) {
+        fs::remove_file(old_backup).with_context(|| "failed to remove old backup")?;
+    }
+
+    Ok(())
+}
+
+/// Determines whether or not the given two serialized sets are identical, ignoring the order their cards were written in
+/// (which is arbitrary unless the set's save options say otherwise).
+#[cfg(feature = "cli")]
+fn same_set(a: &[u8], b: &[u8], format: forne::SetFormat) -> bool {
+    use forne::SetFormat;
+
+    if a == b {
+        return true;
+    }
+    let parse = |bytes: &[u8]| -> Option<serde_json::Value> {
+        match format {
+            SetFormat::Json => serde_json::from_slice(bytes).ok(),
+            SetFormat::Cbor => ciborium::from_reader(bytes).ok(),
+        }
+    };
+    // Objects are compared regardless of the order of their keys
+    match (parse(a), parse(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => false,
+    }
+}
+
+/// Describes how long ago something happened, given the time it happened in milliseconds since Unix epoch.
+#[cfg(feature = "cli")]
+fn describe_age(timestamp: u128) -> String {
+    use std::time::SystemTime;
+
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_millis();
+    let secs = now.saturating_sub(timestamp) / 1000;
+    let (amount, unit) = match secs {
+        0..=59 => (secs, "second"),
+        60..=3599 => (secs / 60, "minute"),
+        3600..=86399 => (secs / 3600, "hour"),
+        _ => (secs / 86400, "day"),
+    };
+
+    format!("{} {}(s) ago", amount, unit)
+}
+
+/// An advisory lock on a set file, which prevents other Forne processes from modifying the set while it's held. The lock
+/// is released when this is dropped.
+#[cfg(feature = "cli")]
+struct SetLock {
+    /// The path to the lock file.
+    path: String,
+}
+#[cfg(feature = "cli")]
+impl Drop for SetLock {
+    fn drop(&mut self) {
+        // There's nothing useful we can do if this fails
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Locks the set stored at the given path by creating a lock file next to it containing the current process ID. If
+/// another process that's still running already holds the lock, this will fail, unless `force` is set, in which case the
+/// lock will be taken anyway. Locks left behind by processes that were killed are taken over automatically, but `force`
+/// is still useful on platforms where we can't tell whether a process is running.
+#[cfg(feature = "cli")]
+fn lock_set(path: &str, force: bool) -> anyhow::Result<SetLock> {
+    use anyhow::{bail, Context};
+    use std::{
+        fs::{self, OpenOptions},
+        io::{ErrorKind, Write},
+    };
+
+    let lock_path = format!("{}.lock", path);
+    let mut file = match OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&lock_path)
+    {
+        Ok(file) => file,
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => {
+            let pid = fs::read_to_string(&lock_path).unwrap_or_default();
+            // If the process that took the lock has since died (e.g. it was killed), the lock is stale and we can take
+            // it over
+            let holder_alive = pid.trim().parse::<u32>().map(process_alive).unwrap_or(true);
+            if !force && holder_alive {
+                bail!(
+                    "this set is already in use by another forne process (pid {}); if you're sure it isn't, run this command again with `--force` to override the lock",
+                    pid.trim()
+                );
+            }
+            OpenOptions::new()
+                .write(true)
+                .truncate(true)
+                .open(&lock_path)
+                .with_context(|| "failed to take over lock file")?
+        }
+        Err(err) => return Err(err).with_context(|| "failed to create lock file"),
+    };
+    write!(file, "{}", std::process::id()).with_context(|| "failed to write to lock file")?;
+
+    Ok(SetLock { path: lock_path })
+}
+
+/// Determines whether or not the process with the given ID is still running. Where we can't tell, this assumes it is.
+#[cfg(feature = "cli")]
+fn process_alive(pid: u32) -> bool {
+    if pid == std::process::id() {
+        // A lock can't have been left by this process, so its ID must have been reused
+        false
+    } else if cfg!(target_os = "linux") {
+        std::path::Path::new(&format!("/proc/{}", pid)).exists()
+    } else if cfg!(unix) {
+        std::process::Command::new("kill")
+            .args(["-0", &pid.to_string()])
+            .stderr(std::process::Stdio::null())
+            .status()
+            .map(|status| status.success())
+            .unwrap_or(true)
+    } else {
+        true
+    }
+}
+
+/// Gets the path to the session journal for the set stored at the given path.
+#[cfg(feature = "cli")]
+fn journal_path(path: &str) -> String {
+    format!("{}.journal", path)
+}
+
+/// Prints a one-line summary of the given update report, for an update run with the given removal policy.
+#[cfg(feature = "cli")]
+fn print_update_summary(report: &forne::UpdateReport, policy: forne::RemovalPolicy) {
+    use forne::{RemovalPolicy, UpdateStatus};
+
+    println!(
+        "Set updated successfully! {} card(s) added, {} changed (progress reset), {} reworded, {} unchanged, {} {}.",
+        report.count(UpdateStatus::Added),
+        report.count(UpdateStatus::Changed),
+        report.count(UpdateStatus::Reworded),
+        report.count(UpdateStatus::Unchanged),
+        report.count(UpdateStatus::Removed),
+        match policy {
+            RemovalPolicy::Keep => "no longer in the source (kept)",
+            RemovalPolicy::Archive => "archived",
+            RemovalPolicy::Delete => "deleted",
+        }
+    );
+}
+
+/// Watches the source file (and adapter script, if it isn't embedded) recorded in the set stored at the given path, updating
+/// the set whenever either of them changes. This runs until the user stops it, and failed updates will be reported without
+/// stopping it. The set is backed up once, before the first update, rather than on every update, so a long session
+/// doesn't push the backups from before it out.
+#[cfg(feature = "cli")]
+fn watch(
+    set_file: &str,
+    policy: forne::RemovalPolicy,
+    force: bool,
+    format: Option<forne::SetFormat>,
+    backups: usize,
+) -> anyhow::Result<()> {
+    use anyhow::{bail, Context};
+    use forne::{AdapterSource, Forne};
+    use notify::{EventKind, RecursiveMode, Watcher};
+    use std::{fs, sync::mpsc, thread, time::Duration};
+
+    let set = read_set(set_file, format)?;
+    let mut watched = Vec::new();
+    match &set.sources.source {
+        Some(source) => watched.push(source.clone()),
+        None => bail!("this set doesn't record where its source file is, please run `forne update` with `--source` first"),
+    }
+    if let Some(AdapterSource::Path(adapter)) = &set.sources.adapter {
+        watched.push(adapter.clone());
+    }
+    // The events we get will have full paths
+    let watched = watched
+        .into_iter()
+        .map(|path| {
+            let path = recorded_path(set_file, &path);
+            fs::canonicalize(&path).with_context(|| format!("failed to find '{}'", path.display()))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let (tx, rx) = mpsc::channel();
+    let mut watcher =
+        notify::recommended_watcher(tx).with_context(|| "failed to create file watcher")?;
+    // Many editors save files by replacing them, so we have to watch the directories they're in
+    for path in watched.iter() {
+        let dir = path
+            .parent()
+            .with_context(|| "source file has no parent directory")?;
+        watcher
+            .watch(dir, RecursiveMode::NonRecursive)
+            .with_context(|| format!("failed to watch '{}'", dir.display()))?;
+    }
+    println!(
+        "Watching {} for changes (press Ctrl+C to stop)...",
+        watched[0].display()
+    );
+
+    let mut backed_up = false;
+    // Only the first update should override an existing lock: after that, anything holding the lock took it while we
+    // were watching, so we'll leave it be and catch up on the next change
+    let mut force = force;
+    for res in &rx {
+        let event = res.with_context(|| "failed to watch source file")?;
+        let relevant = matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+            && event.paths.iter().any(|path| watched.contains(path));
+        if !relevant {
+            continue;
+        }
+        // Editors often write files in several steps, so wait for them to finish and ignore the rest of the events
+        thread::sleep(Duration::from_millis(200));
+        while rx.try_recv().is_ok() {}
+
+        let mut update = || -> anyhow::Result<forne::UpdateReport> {
+            let lock = lock_set(set_file, force)?;
+            force = false;
+            let mut set = recover_set(set_file, format, &lock)?;
+            let source = resolve_source(set_file, None, &mut set.sources)?;
+            let adapter_script = resolve_adapter(set_file, None, false, &mut set.sources)?;
+            let method = resolve_method(set_file, None, &mut set)?;
+
+            let mut forne = Forne::from_set(set);
+            let report = forne.update(source, &adapter_script, method, policy)?;
+            let bytes = forne.save_set_as(set_format(set_file, format))?;
+            if !backed_up {
+                backup_set(set_file, format, backups)?;
+                backed_up = true;
+            }
+            write_set(set_file, &bytes).with_context(|| "failed to write updated set")?;
+
+            Ok(report)
+        };
+        match update() {
+            Ok(report) => print_update_summary(&report, policy),
+            Err(err) => eprintln!("Failed to update set: {:#}", err),
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the changes in the given update report, colour-coded by what will happen to each card. Unchanged cards are
+/// not printed.
+#[cfg(feature = "cli")]
+fn print_report(report: &forne::UpdateReport) -> anyhow::Result<()> {
+    use forne::UpdateStatus;
+    use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
+
+    let mut green = ColorSpec::new();
+    green.set_fg(Some(Color::Green));
+    let mut yellow = ColorSpec::new();
+    yellow.set_fg(Some(Color::Yellow));
+    let mut red = ColorSpec::new();
+    red.set_fg(Some(Color::Red));
+    let mut magenta = ColorSpec::new();
+    magenta.set_fg(Some(Color::Magenta));
+    let mut cyan = ColorSpec::new();
+    cyan.set_fg(Some(Color::Cyan));
+
+    let mut stdout = StandardStream::stdout(ColorChoice::Always);
+    for card in report.cards.iter() {
+        match card.status {
+            UpdateStatus::Added => {
+                stdout.set_color(&green)?;
+                println!("+ Q: {}", card.question);
+                println!("  A: {}", card.answer);
+            }
+            UpdateStatus::Reworded => {
+                stdout.set_color(&cyan)?;
+                println!(
+                    "~ Q: {} -> {}",
+                    card.previous_question.as_deref().unwrap_or_default(),
+                    card.question
+                );
+                println!("  A: {}", card.answer);
+            }
+            UpdateStatus::Changed => {
+                stdout.set_color(&yellow)?;
+                if let Some(previous_question) = &card.previous_question {
+                    println!("~ Q: {} -> {}", previous_question, card.question);
+                } else {
+                    println!("~ Q: {}", card.question);
+                }
+                println!(
+                    "  A: {} -> {} (progress will be reset)",
+                    card.previous_answer.as_deref().unwrap_or_default(),
+                    card.answer
+                );
+            }
+            UpdateStatus::Removed => {
+                stdout.set_color(&red)?;
+                println!("- Q: {}", card.question);
+                println!("  A: {}", card.answer);
+            }
+            // Cards coming back out of the archive are worth mentioning even if they haven't changed
+            UpdateStatus::Unchanged if card.unarchived => {
+                stdout.set_color(&green)?;
+                println!("^ Q: {}", card.question);
+                println!("  A: {} (restored from the archive)", card.answer);
+            }
+            UpdateStatus::Unchanged => {}
+        }
+    }
+    stdout.set_color(&magenta)?;
+    for question in report.duplicates.iter() {
+        println!(
+            "! Duplicate question (only the first will be used): {}",
+            question
+        );
+    }
+    stdout.reset()?;
+
+    println!(
+        "{} card(s) would be added, {} changed (progress reset), {} reworded, {} unchanged, {} no longer in the source.",
+        report.count(UpdateStatus::Added),
+        report.count(UpdateStatus::Changed),
+        report.count(UpdateStatus::Reworded),
+        report.count(UpdateStatus::Unchanged),
+        report.count(UpdateStatus::Removed),
+    );
+
+    Ok(())
+}
+
 /// Creates a `RawMethod` from a string provided on the command line that might either be the name of an inbuilt method
 /// or the path to a custom Rhai script.
 ///
@@ -184,17 +977,211 @@ fn method_from_string(method_str: String) -> anyhow::Result<forne::RawMethod> {
     }
 }
 
+/// Gets the full path to a file recorded in the set stored at the given path, which will be relative to the set file
+/// (unless it's absolute).
+#[cfg(feature = "cli")]
+fn recorded_path(set_file: &str, path: &std::path::Path) -> std::path::PathBuf {
+    use std::path::Path;
+
+    // If the set file has no parent directory, this will just be the path itself
+    Path::new(set_file)
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(path)
+}
+
+/// Works out the path to record in the set stored at the given path for the given file, which will be relative to the
+/// set file, so a set and the files it was created from can be moved around together (e.g. in a repository). If there is
+/// no relative path between them (e.g. if they're on different drives), the full path will be recorded.
+#[cfg(feature = "cli")]
+fn path_to_record(set_file: &str, path: &std::path::Path) -> std::io::Result<std::path::PathBuf> {
+    use std::{
+        fs,
+        path::{Path, PathBuf},
+    };
+
+    let path = fs::canonicalize(path)?;
+    let set_dir = match Path::new(set_file).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => fs::canonicalize(dir)?,
+        _ => fs::canonicalize(".")?,
+    };
+
+    let path_parts = path.components().collect::<Vec<_>>();
+    let dir_parts = set_dir.components().collect::<Vec<_>>();
+    let num_common = path_parts
+        .iter()
+        .zip(dir_parts.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+    if num_common == 0 {
+        return Ok(path);
+    }
+    let mut relative = PathBuf::new();
+    for _ in num_common..dir_parts.len() {
+        relative.push("..");
+    }
+    relative.extend(&path_parts[num_common..]);
+
+    Ok(relative)
+}
+
+/// Updates the paths recorded in the given set sources, which are relative to the file at `from`, to be relative to the
+/// file at `to`, for when a set is being written somewhere else. Any paths that no longer lead anywhere are left as they
+/// are.
+#[cfg(feature = "cli")]
+fn move_sources(sources: &mut forne::SetSources, from: &str, to: &str) {
+    use forne::AdapterSource;
+    use std::path::PathBuf;
+
+    let move_path = |path: &mut PathBuf| {
+        if let Ok(new_path) = path_to_record(to, &recorded_path(from, path)) {
+            *path = new_path;
+        }
+    };
+    if let Some(source) = &mut sources.source {
+        move_path(source);
+    }
+    if let Some(AdapterSource::Path(adapter)) = &mut sources.adapter {
+        move_path(adapter);
+    }
+    sources.methods.values_mut().for_each(move_path);
+}
+
+/// Works out which method to use for the given set. If one was given on the command line, it will be used (and, if it's a
+/// custom script, its path will be recorded in the set), and otherwise the method most recently used on the set will be.
+#[cfg(feature = "cli")]
+fn resolve_method(
+    set_file: &str,
+    method: Option<String>,
+    set: &mut forne::Set,
+) -> anyhow::Result<forne::RawMethod> {
+    use anyhow::{bail, Context};
+    use forne::RawMethod;
+    use std::fs;
+
+    if let Some(method_str) = method {
+        let raw_method = method_from_string(method_str.clone())?;
+        record_method(set_file, &method_str, &raw_method, &mut set.sources);
+        return Ok(raw_method);
+    }
+
+    if RawMethod::is_inbuilt(&set.method) {
+        Ok(RawMethod::Inbuilt(set.method.clone()))
+    } else if let Some(path) = set.sources.methods.get(&set.method) {
+        let body = fs::read_to_string(recorded_path(set_file, path)).with_context(|| {
+            format!(
+                "failed to read script for custom method '{}' from '{}'",
+                set.method,
+                path.display()
+            )
+        })?;
+        Ok(RawMethod::Custom {
+            name: set.method.clone(),
+            body,
+        })
+    } else {
+        bail!("this set was last learned with the custom method '{}', but forne doesn't know where its script is, please provide it with `--method`", set.method)
+    }
+}
+
+/// Records the path to the given method's script in the given sources of the set stored at the given path, if it's a
+/// custom method.
+#[cfg(feature = "cli")]
+fn record_method(
+    set_file: &str,
+    method_str: &str,
+    raw_method: &forne::RawMethod,
+    sources: &mut forne::SetSources,
+) {
+    use std::path::Path;
+
+    if let forne::RawMethod::Custom { name, .. } = raw_method {
+        // We've already read the script successfully, so this should never fail
+        if let Ok(path) = path_to_record(set_file, Path::new(method_str)) {
+            sources.methods.insert(name.clone(), path);
+        }
+    }
+}
+
+/// Reads the source file of a set, using the given path if there is one (and recording it in the given set sources), and
+/// otherwise the path recorded in the sources.
+#[cfg(feature = "cli")]
+fn resolve_source(
+    set_file: &str,
+    source: Option<String>,
+    sources: &mut forne::SetSources,
+) -> anyhow::Result<String> {
+    use anyhow::{bail, Context};
+    use std::{fs, path::Path};
+
+    if let Some(source) = source {
+        sources.source = Some(
+            path_to_record(set_file, Path::new(&source))
+                .with_context(|| "failed to find source file")?,
+        );
+    }
+    match &sources.source {
+        Some(path) => fs::read_to_string(recorded_path(set_file, path))
+            .with_context(|| format!("failed to read from source file '{}'", path.display())),
+        None => bail!(
+            "this set doesn't record where its source file is, please provide it with `--source`"
+        ),
+    }
+}
+
+/// Reads the adapter script of a set, using the given path if there is one (and recording it in the given set sources), and
+/// otherwise the adapter recorded in the sources. If `embed` is set, the adapter will be recorded by embedding its full
+/// body, rather than its path.
+#[cfg(feature = "cli")]
+fn resolve_adapter(
+    set_file: &str,
+    adapter: Option<std::path::PathBuf>,
+    embed: bool,
+    sources: &mut forne::SetSources,
+) -> anyhow::Result<String> {
+    use anyhow::{bail, Context};
+    use forne::AdapterSource;
+    use std::fs;
+
+    if let Some(adapter) = adapter {
+        sources.adapter = Some(AdapterSource::Path(
+            path_to_record(set_file, &adapter).with_context(|| "failed to find adapter script")?,
+        ));
+    }
+    let script = match &sources.adapter {
+        Some(AdapterSource::Path(path)) => fs::read_to_string(recorded_path(set_file, path))
+            .with_context(|| format!("failed to read adapter script '{}'", path.display()))?,
+        Some(AdapterSource::Embedded(script)) => script.clone(),
+        None => bail!(
+            "this set doesn't record what adapter it uses, please provide one with `--adapter`"
+        ),
+    };
+    if embed {
+        sources.adapter = Some(AdapterSource::Embedded(script.clone()));
+    }
+
+    Ok(script)
+}
+
 /// Displays questions and answers, receiving input from the user and continuing a learning/testing session. This takes
 /// both a driver and the input file that the set is stored in, so it can be periodically saved to prevent lost progress.
+/// Before the set is first saved, it will be backed up (keeping the given number of backups).
 ///
 /// This returns the number of cards reviewed.
 #[cfg(feature = "cli")]
-fn drive<'a>(mut driver: forne::Driver<'a, 'a>, set_file: &str) -> anyhow::Result<u32> {
+fn drive<'a>(
+    mut driver: forne::Driver<'a, 'a>,
+    set_file: &str,
+    format: forne::SetFormat,
+    journal: bool,
+    backups: usize,
+) -> anyhow::Result<u32> {
     use anyhow::{bail, Context};
     use crossterm::{terminal, ExecutableCommand};
     use std::{
-        fs,
+        fs::{self, OpenOptions},
         io::{self, Write},
+        time::Instant,
     };
     use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
@@ -207,12 +1194,29 @@ fn drive<'a>(mut driver: forne::Driver<'a, 'a>, set_file: &str) -> anyhow::Resul
     let mut stdout = StandardStream::stdout(ColorChoice::Always);
 
     let mut card_option = driver.first()?;
+    // Now the session has started successfully, we're about to write to the set
+    backup_set(set_file, Some(format), backups)?;
+    // In journal mode, we save the set once up front, and then just append each change to the journal
+    let mut journal_file = if journal {
+        let bytes = driver.save_set_as(format)?;
+        write_set(set_file, &bytes).with_context(|| "failed to save set")?;
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(journal_path(set_file))
+            .with_context(|| "failed to open session journal")?;
+        Some(file)
+    } else {
+        None
+    };
     while let Some(card) = card_option {
         // Save the set quickly
-        let json = driver.save_set_to_json()?;
-        fs::write(set_file, json).with_context(|| {
-            "failed to save set to json (progress up to the previous card was saved though)"
-        })?;
+        if journal_file.is_none() {
+            let bytes = driver.save_set_as(format)?;
+            write_set(set_file, &bytes).with_context(|| {
+                "failed to save set (progress up to the previous card was saved though)"
+            })?;
+        }
 
         stdout.set_color(&yellow)?;
         print!(
@@ -220,13 +1224,26 @@ fn drive<'a>(mut driver: forne::Driver<'a, 'a>, set_file: &str) -> anyhow::Resul
             if card.starred { "⦿ " } else { "" },
             card.question
         );
+        if card.hint.is_some() {
+            print!(" (enter ? for a hint)");
+        }
         stdout.flush()?;
-        // Wait for the user to press enter
-        let res = stdin.read_line(&mut String::new());
+        // Wait for the user to press enter (timing how long it takes them to recall the answer)
+        let shown_at = Instant::now();
+        let mut input = String::new();
+        let res = stdin.read_line(&mut input);
         // If the user wants to end the run, let them (their progress will be saved)
         if let Ok(0) = res {
             break;
         }
+        if let Some(hint) = card.hint.as_ref().filter(|_| input.trim() == "?") {
+            print!("Hint: {}", hint);
+            stdout.flush()?;
+            if let Ok(0) = stdin.read_line(&mut String::new()) {
+                break;
+            }
+        }
+        let latency = shown_at.elapsed();
 
         stdout.set_color(&green)?;
         println!("A: {}", card.answer);
@@ -257,14 +1274,24 @@ fn drive<'a>(mut driver: forne::Driver<'a, 'a>, set_file: &str) -> anyhow::Resul
         stdout.execute(terminal::Clear(terminal::ClearType::All))?;
 
         // This will adjust weights etc. and get us a new card, if one exists
-        card_option = driver.next(res)?;
+        card_option = driver.next_with_latency(res, latency)?;
+        if let (Some(file), Some(entry)) = (journal_file.as_mut(), driver.journal_entry()?) {
+            writeln!(file, "{}", entry).with_context(|| {
+                "failed to write to session journal (progress up to the previous card was saved though)"
+            })?;
+        }
     }
     stdout.reset()?;
 
-    let json = driver.save_set_to_json()?;
-    fs::write(set_file, json).with_context(|| {
-        "failed to save set to json (progress up to the previous card was saved though)"
+    let bytes = driver.save_set_as(format)?;
+    write_set(set_file, &bytes).with_context(|| {
+        "failed to save set (progress up to the previous card was saved though)"
     })?;
+    // Now that everything's in the set, the journal is no longer needed
+    if journal_file.is_some() {
+        fs::remove_file(journal_path(set_file))
+            .with_context(|| "failed to remove session journal")?;
+    }
     Ok(driver.get_count())
 }
 
@@ -302,7 +1329,8 @@ mod opts {
     use std::path::PathBuf;
 
     use clap::{Parser, Subcommand};
-    use forne::CardType;
+    use forne::{CardOrder, CardType, Query, SetFormat};
+    use rhai::{Dynamic, Engine};
 
     /// Forne: a spaced repetition CLI to help you learn stuff
     #[derive(Parser, Debug)]
@@ -310,6 +1338,24 @@ mod opts {
     pub struct Args {
         #[clap(subcommand)]
         pub command: Command,
+        /// The format set files are stored in (`json` or `cbor`); by default, files ending in `.cbor` are treated as CBOR,
+        /// and everything else as JSON
+        #[arg(long, global = true, value_enum)]
+        pub format: Option<SetFormat>,
+        /// The number of backups to keep of each set (one is taken every time a set is modified, and they can be restored
+        /// with `forne restore`); set this to 0 to disable backups
+        #[arg(long, global = true, default_value_t = 5)]
+        pub backups: usize,
+    }
+
+    /// The options for commands that lock the sets they write to, so that other forne processes don't write to them at the
+    /// same time.
+    #[derive(clap::Args, Debug)]
+    pub struct LockArgs {
+        /// Override the lock on the set if another forne process appears to be using it (only do this if you're sure it
+        /// isn't, e.g. if forne crashed)
+        #[arg(long)]
+        pub force: bool,
     }
 
     #[derive(Subcommand, Debug)]
@@ -326,37 +1372,99 @@ mod opts {
             /// The learning method to use for the new set
             #[arg(short, long)]
             method: String, // Secondary parsing
+            /// Store the full adapter script in the set, rather than its path, so the set can be updated without it
+            #[arg(long)]
+            embed_adapter: bool,
+            /// The date of the exam you're studying for (as `YYYY-MM-DD`), which methods designed for cramming (like `cram`)
+            /// use to plan your reviews; this will be remembered
+            #[arg(long, value_parser = parse_date)]
+            exam_date: Option<i64>,
+            /// A file containing progress on another copy of this set (from `forne split`), which will be applied to the new set
+            #[arg(short, long)]
+            progress: Option<String>,
+            #[command(flatten)]
+            lock: LockArgs,
         },
         /// Updates an existing set with some new terms
         Update {
             /// The existing set file
             set: String,
-            /// The file to update the set with
+            /// The file to update the set with (by default, the one the set was created or last updated from)
             #[arg(short, long)]
-            source: String,
-            /// The path to the adapter script to be used to parse the set
+            source: Option<String>,
+            /// The path to the adapter script to be used to parse the set (by default, the one the set was created or last
+            /// updated with)
             #[arg(short, long)]
-            adapter: PathBuf,
-            /// The learning method to use for the new set
+            adapter: Option<PathBuf>,
+            /// The learning method to use for any new cards (by default, the one the set was last learned with)
             #[arg(short, long)]
-            method: String, // Secondary parsing
+            method: Option<String>, // Secondary parsing
+            /// Store the full adapter script in the set, rather than its path, so the set can be updated without it
+            #[arg(long)]
+            embed_adapter: bool,
+            /// Delete any cards that are no longer in the source, irretrievably deleting their progress
+            #[arg(long, conflicts_with = "archive")]
+            prune: bool,
+            /// Move any cards that are no longer in the source into the set's archive, where their progress will be kept (and
+            /// restored if they come back into the source)
+            #[arg(long)]
+            archive: bool,
+            /// Print what the update would change without modifying the set
+            #[arg(long)]
+            dry_run: bool,
+            #[command(flatten)]
+            lock: LockArgs,
+        },
+        /// Watches the source file a set was created from, updating the set whenever it changes
+        Watch {
+            /// The file the set is in
+            set: String,
+            /// Move any cards that are no longer in the source into the set's archive, where their progress will be kept (and
+            /// restored if they come back into the source). Cards can't be deleted outright while watching, since a
+            /// half-saved source file could delete most of the set: use `forne update --prune` for that.
+            #[arg(long)]
+            archive: bool,
+            #[command(flatten)]
+            lock: LockArgs,
         },
         /// Starts or resumes a learning session on the given set
         Learn {
             /// The file the set is in
             set: String,
-            /// The learning method to use
+            /// The learning method to use (by default, the one the set was last learned with)
             #[arg(short, long)]
-            method: String, // Secondary parsing
-            /// The type of cards to operate on (`all`, `difficult`, or `starred`)
-            #[arg(short, long = "type", value_enum, default_value = "all")]
+            method: Option<String>, // Secondary parsing
+            /// The type of cards to operate on (`all`, `difficult`, `starred`, or `tag:` followed by comma-separated tags)
+            #[arg(short, long = "type", default_value = "all")]
             ty: CardType,
+            /// A query selecting the cards to operate on (e.g. `starred and tag:verbs and not difficult`), which will be
+            /// combined with `--type`
+            #[arg(short, long)]
+            query: Option<Query>,
             /// Limit the number of terms studied to the given amount (useful for consistent long-term learning); your progress will be saved
             #[arg(short, long)]
             count: Option<u32>,
             /// Starts a new learn session from scratch, irretrievably deleting any progress in a previous session
             #[arg(long)]
             reset: bool,
+            /// Record progress in a journal next to the set instead of rewriting the whole set after every card (much faster for
+            /// large sets); the journal is merged into the set when the session ends, or the next time the set is used if it crashes
+            #[arg(long)]
+            journal: bool,
+            #[command(flatten)]
+            lock: LockArgs,
+            /// The date of the exam you're studying for (as `YYYY-MM-DD`), which methods designed for cramming (like `cram`)
+            /// use to plan your reviews; this will be remembered
+            #[arg(long, value_parser = parse_date)]
+            exam_date: Option<i64>,
+            /// Sets one of the method's parameters for this set (e.g. `--param initial_easiness=2.2`), which will be
+            /// remembered; this can be given several times
+            #[arg(long = "param", value_parser = parse_parameter)]
+            params: Vec<(String, Dynamic)>,
+            /// Use a custom method's script even though it has changed since it was last used with this set (only do this
+            /// if the changes don't affect the data the method stores, otherwise use `forne migrate`)
+            #[arg(long)]
+            accept_changes: bool,
         },
         /// Starts or resumes a test on the given set
         Test {
@@ -372,25 +1480,226 @@ mod opts {
             /// Do not unstar cards you get right if they're currently starred (useful to review cards without losing which ones you're consistently getting wrong)
             #[arg(long)]
             no_unstar: bool,
-            /// The type of cards to operate on (`all`, `difficult`, or `starred`)
-            #[arg(short, long = "type", value_enum, default_value = "all")]
+            /// The type of cards to operate on (`all`, `difficult`, `starred`, or `tag:` followed by comma-separated tags)
+            #[arg(short, long = "type", default_value = "all")]
             ty: CardType,
+            /// A query selecting the cards to operate on (e.g. `starred and tag:verbs and not difficult`), which will be
+            /// combined with `--type`
+            #[arg(short, long)]
+            query: Option<Query>,
             /// Limit the number of terms studied to the given amount (useful for consistent long-term learning); your progress will be saved
             #[arg(short, long)]
             count: Option<u32>,
             /// Starts a new test from scratch, irretrievably deleting any progress in a previous test
             #[arg(long)]
             reset: bool,
+            /// Record progress in a journal next to the set instead of rewriting the whole set after every card (much faster for
+            /// large sets); the journal is merged into the set when the session ends, or the next time the set is used if it crashes
+            #[arg(long)]
+            journal: bool,
+            #[command(flatten)]
+            lock: LockArgs,
+        },
+        /// Migrates your progress on the given set from one learning method to another, using the new method's transformer
+        Migrate {
+            /// The file the set is in
+            set: String,
+            /// The learning method to migrate to (which must define a transformer); this can be a new version of the
+            /// custom method the set was last learned with, if its script has changed
+            #[arg(short, long)]
+            method: String, // Secondary parsing
+            /// The name of the learning method to migrate from (defaults to the method most recently used on the set)
+            #[arg(short, long)]
+            from: Option<String>,
+            #[command(flatten)]
+            lock: LockArgs,
+        },
+        /// Merges another copy of a set (e.g. from another device) into the given set, keeping the most recent progress on each card
+        Merge {
+            /// The file the set is in (the merged set will be written here)
+            set: String,
+            /// The file the other copy of the set is in (this won't be modified)
+            other: String,
+            #[command(flatten)]
+            lock: LockArgs,
+        },
+        /// Restores a set from one of its automatic backups
+        Restore {
+            /// The file the set is in
+            set: String,
+            /// The name of the backup to restore (as given by `--list`); by default, the most recent backup will be restored
+            backup: Option<String>,
+            /// List the available backups instead of restoring one
+            #[arg(short, long)]
+            list: bool,
+            #[command(flatten)]
+            lock: LockArgs,
+        },
+        /// Splits a set into its content and the progress you've made on it, so you can share the content without your progress
+        Split {
+            /// The file the set is in
+            set: String,
+            /// The file to write the set's content to (as JSON)
+            #[arg(short, long)]
+            content: String,
+            /// The file to write your progress on the set to (as JSON)
+            #[arg(short, long)]
+            progress: String,
+        },
+        /// Joins a set's content and progress (as produced by `forne split`) back into a single set
+        Join {
+            /// The file the set's content is in
+            content: String,
+            /// The file your progress on the set is in
+            progress: String,
+            /// The file to write the joined set to
+            #[arg(short, long)]
+            output: String,
+            #[command(flatten)]
+            lock: LockArgs,
+        },
+        /// Converts a set from one format to another (e.g. from JSON to the more compact CBOR format)
+        Convert {
+            /// The file the set is in
+            input: String,
+            /// The file to write the converted set to
+            output: String,
+            /// The format to convert the set to (by default, this is worked out from the output file's extension)
+            #[arg(short, long, value_enum)]
+            to: Option<SetFormat>,
+            #[command(flatten)]
+            lock: LockArgs,
+        },
+        /// Changes how the given set is saved (e.g. to make it friendlier to version control); this will apply to all future saves
+        Format {
+            /// The file the set is in
+            set: String,
+            /// Pretty-print the set, with each property on its own line (if not given, the set will be saved compactly)
+            #[arg(short, long)]
+            pretty: bool,
+            /// The order to save cards in (`id` or `source`); if not given, cards will be saved in an arbitrary order
+            #[arg(short, long, value_enum)]
+            order: Option<CardOrder>,
+            #[command(flatten)]
+            lock: LockArgs,
+        },
+        /// Adds or removes tags on a card in the given set
+        Tag {
+            /// The file the set is in
+            set: String,
+            /// The question on the card to tag
+            question: String,
+            /// Tags to add to the card (hierarchical tags can be separated with `::`)
+            #[arg(short, long)]
+            add: Vec<String>,
+            /// Tags to remove from the card
+            #[arg(short, long)]
+            remove: Vec<String>,
+            #[command(flatten)]
+            lock: LockArgs,
         },
         /// Lists all the terms in the given set
         List {
             /// The file the set is in
             set: String,
-            /// The type of cards to operate on (`all`, `difficult`, or `starred`)
-            #[arg(short, long = "type", value_enum, default_value = "all")]
+            /// The type of cards to operate on (`all`, `difficult`, `starred`, or `tag:` followed by comma-separated tags)
+            #[arg(short, long = "type", default_value = "all")]
             ty: CardType,
+            /// A query selecting the cards to operate on (e.g. `starred and tag:verbs and not difficult`), which will be
+            /// combined with `--type`
+            #[arg(short, long)]
+            query: Option<Query>,
         },
     }
+
+    /// Parses a method parameter in the form `name=value`. The value can be any Rhai literal (e.g. `2.5` or `[1, 3, 7]`),
+    /// and anything else will be treated as a string.
+    fn parse_parameter(param: &str) -> Result<(String, Dynamic), String> {
+        let (name, value) = param.split_once('=').ok_or_else(|| {
+            format!("invalid parameter '{param}', expected the form `name=value`")
+        })?;
+        let value = Engine::new_raw()
+            .eval_expression::<Dynamic>(value)
+            .unwrap_or_else(|_| Dynamic::from(value.to_string()));
+
+        Ok((name.trim().to_string(), value))
+    }
+    /// Parses a date in the form `YYYY-MM-DD` into the number of seconds since Unix epoch at the start of that day (in UTC).
+    fn parse_date(date: &str) -> Result<i64, String> {
+        let err = || format!("invalid date '{date}', expected the form `YYYY-MM-DD`");
+        let parts = date
+            .split('-')
+            .map(|part| part.parse::<i64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| err())?;
+        let (year, month, day) = match parts[..] {
+            [year, month, day] if (1..=12).contains(&month) => (year, month, day),
+            _ => return Err(err()),
+        };
+        let leap_year = year % 4 == 0 && (year % 100 != 0 || year % 400 == 0);
+        let days_in_month = match month {
+            2 if leap_year => 29,
+            2 => 28,
+            4 | 6 | 9 | 11 => 30,
+            _ => 31,
+        };
+        if !(1..=days_in_month).contains(&day) {
+            return Err(format!(
+                "invalid date '{date}', there are only {days_in_month} days in that month"
+            ));
+        }
+
+        // This counts days using a calendar starting in March, so leap days come at the end of the year (see
+        // http://howardhinnant.github.io/date_algorithms.html#days_from_civil)
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146097 + day_of_era - 719468;
+
+        Ok(days * 86400)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::parse_date;
+
+        #[test]
+        fn dates_are_converted_to_timestamps() {
+            assert_eq!(parse_date("1970-01-01"), Ok(0));
+            assert_eq!(parse_date("2000-03-01"), Ok(951868800));
+            assert_eq!(parse_date("2024-12-31"), Ok(1735603200));
+            assert_eq!(parse_date("1969-12-31"), Ok(-86400));
+        }
+
+        #[test]
+        fn leap_days_are_only_allowed_in_leap_years() {
+            assert_eq!(parse_date("2024-02-29"), Ok(1709164800));
+            assert_eq!(parse_date("2000-02-29"), Ok(951782400));
+            assert!(parse_date("2023-02-29").is_err());
+            // Centuries are only leap years if they're divisible by 400
+            assert!(parse_date("1900-02-29").is_err());
+            assert!(parse_date("2100-02-29").is_err());
+        }
+
+        #[test]
+        fn impossible_dates_are_rejected() {
+            for date in [
+                "2026-02-31",
+                "2026-04-31",
+                "2026-13-01",
+                "2026-00-10",
+                "2026-01-00",
+                "2026-01-32",
+                "2026-01",
+                "2026-01-01-01",
+                "tomorrow",
+            ] {
+                assert!(parse_date(date).is_err(), "'{date}' should be invalid");
+            }
+        }
+    }
 }
 
 /*
@@ -1,11 +1,16 @@
 use crate::{
+    format::SetFormat,
+    journal::JournalEntry,
     methods::{Method, RawMethod},
-    set::{Card, CardType, Set, SlimCard},
+    query::Query,
+    seconds_since_epoch,
+    set::{CardType, Review, ReviewMode, Set, SlimCard},
 };
 use anyhow::{bail, Error, Result};
 use lazy_static::lazy_static;
-use rand::{distributions::WeightedError, seq::SliceRandom};
+use rand::distributions::{Distribution, WeightedError, WeightedIndex};
 use rhai::Engine;
+use std::time::Duration;
 use uuid::Uuid;
 
 lazy_static! {
@@ -28,12 +33,16 @@ pub struct Driver<'e, 's> {
     // We can't store a mutable reference to the latest card directly here, because the lifetimes wouldn't work out at all, and this
     // is a really subtle bug that Rust picks up on uniquely and superbly!
     latest_card: Option<Uuid>,
+    /// The unique identifier of the last card adjusted by `.next()`, which is used to produce journal records.
+    last_adjusted: Option<Uuid>,
     /// The maximum number of elements to review, if one has been set.
     max_count: Option<u32>,
     /// The number of cards we've reviewed so far.
     curr_count: u32,
     /// The type of cards to be targeted by this driver.
     target: CardType,
+    /// A query that cards must match to be targeted by this driver, in addition to the target type, if one has been set.
+    query: Option<Query>,
 
     /// Whether or not we should mark cards that the user gets wrong as starred in tests.
     mark_starred: bool,
@@ -49,33 +58,44 @@ impl<'e, 's> Driver<'e, 's> {
     /// Creates a new driver with the given set and method, with the latter provided as either the name of an inbuilt method or the body of
     /// a custom Rhai script.
     ///
-    /// # Errors
-    ///
-    /// This will return an error if the given method has not previously been used with this set, and a reset must be performed in that case,
-    /// which will lead to the loss of previous progress, unless a transformer is used.
+    /// If the given method has not previously been used with this set, any cards without data for it will have that data
+    /// initialised, leaving the data of any other methods untouched. If the method defines a transformer, it will be used
+    /// to convert the data of the method last used on the set, otherwise the method's defaults will be used.
     pub(crate) fn new_learn(
         set: &'s mut Set,
         raw_method: RawMethod,
         engine: &'e Engine,
     ) -> Result<Self> {
-        let method = raw_method.into_method(engine)?;
-        let instance = Self {
+        let parameters = set.method_parameters.get(raw_method.name());
+        let method = raw_method.into_method(parameters, engine)?;
+        set.check_method(&method)?;
+        let previous_method = set.method.clone();
+        for card in set.cards.values_mut() {
+            if !card.method_states.contains_key(&method.name) {
+                let previous = card
+                    .method_states
+                    .get(&previous_method)
+                    .map(|data| (previous_method.as_str(), data));
+                let method_data = method.initial_metadata(previous, card.difficult)?;
+                card.method_states.insert(method.name.clone(), method_data);
+            }
+        }
+        set.method = method.name.clone();
+
+        Ok(Self {
             method: Some(method),
             set,
             max_count: None,
             curr_count: 0,
             target: CardType::All,
+            query: None,
             latest_card: None,
+            last_adjusted: None,
 
             mark_starred: true,
             mutate_difficulty: true,
             mark_unstarred: true,
-        };
-        if !instance.method_correct() {
-            bail!("given method is not the same as the one that has been previously used for this set (please reset the set before attempting to use a new method)");
-        }
-
-        Ok(instance)
+        })
     }
     /// Creates a new driver with the given set, running in test mode. This takes no custom method, as it runs a test, and it is infallible.
     pub(crate) fn new_test(set: &'s mut Set) -> Self {
@@ -85,7 +105,9 @@ impl<'e, 's> Driver<'e, 's> {
             max_count: None,
             curr_count: 0,
             target: CardType::All,
+            query: None,
             latest_card: None,
+            last_adjusted: None,
 
             mark_starred: true,
             mutate_difficulty: true,
@@ -97,6 +119,12 @@ impl<'e, 's> Driver<'e, 's> {
         self.target = target;
         self
     }
+    /// Sets a query that cards must match to be targeted by this driver, in addition to the target type. See [`Query`] for
+    /// details of the query language.
+    pub fn set_query(&mut self, query: Query) -> &mut Self {
+        self.query = Some(query);
+        self
+    }
     /// Sets a maximum number of elements to be reviewed through this driver. This can be useful for long-term learning, in which you only
     /// want to review, say, 30 cards per day.
     ///
@@ -131,15 +159,6 @@ impl<'e, 's> Driver<'e, 's> {
     pub fn get_count(&self) -> u32 {
         self.curr_count
     }
-    /// Performs a sanity check that the method this driver has been instantiated with is the same as the one that has been being used for the set.
-    fn method_correct(&self) -> bool {
-        if let Some(method) = &self.method {
-            method.name == self.set.method
-        } else {
-            // We're running a test
-            true
-        }
-    }
     /// Gets the first question/answer pair of this run. While it is perfectly safe to run this at any time, it
     /// is semantically nonsensical to run this more than once, as Forn's internals will become completely
     /// useless. If you want to display each card to the user only once, irrespective of the metadata attached to
@@ -150,8 +169,8 @@ impl<'e, 's> Driver<'e, 's> {
     /// `.first()` call.)
     ///
     /// This will automatically continue the most recent session of either learning or testing, if there is one.
-    // No instance can be constructed without first checking if the method matches the set, so assuming it does
-    // is perfectly safe here.
+    // No instance can be constructed without first initialising the method's data for every card, so assuming it
+    // exists is perfectly safe here.
     pub fn first(&mut self) -> Result<Option<SlimCard>> {
         let mut rng = rand::thread_rng();
 
@@ -166,60 +185,52 @@ impl<'e, 's> Driver<'e, 's> {
             return Ok(None);
         }
 
-        // Randomly select a card according to the weights generated by the method
-        let mut cards_with_ids = self.set.cards.iter().collect::<Vec<_>>();
-        let (card_id, card) =
-            match cards_with_ids.choose_weighted_mut(&mut rng, |(_, card): &(&Uuid, &Card)| {
+        // Randomly select a card according to the weights generated by the method (which we work out up front, so
+        // any errors in the method can be propagated)
+        let cards_with_ids = self.set.cards.iter().collect::<Vec<_>>();
+        let weights = cards_with_ids
+            .iter()
+            .map(|(_, card)| {
+                let targeted = self.target.matches(card)
+                    && self.query.as_ref().is_none_or(|query| query.matches(card));
                 if let Some(method) = &self.method {
-                    let res = match &self.target {
-                        CardType::All => {
-                            (method.get_weight)(card.method_data.clone(), card.difficult)
-                        }
-                        CardType::Starred if card.starred => {
-                            (method.get_weight)(card.method_data.clone(), card.difficult)
-                        }
-                        CardType::Difficult if card.difficult => {
-                            (method.get_weight)(card.method_data.clone(), card.difficult)
-                        }
-                        _ => Ok(0.0),
-                    };
-                    // TODO handle errors (very realistic that they would occur with custom scripts!)
-                    res.unwrap()
-                } else {
-                    match &self.target {
-                        CardType::All if !card.seen_in_test => 1.0,
-                        CardType::Starred if card.starred && !card.seen_in_test => 1.0,
-                        CardType::Difficult if card.difficult && !card.seen_in_test => 1.0,
-                        _ => 0.0,
-                    }
-                }
-            }) {
-                Ok(data) => data,
-                // We're done!
-                Err(WeightedError::AllWeightsZero) => {
-                    // If we've genuinely finished, say so
-                    if self.method.is_some() {
-                        self.set.run_state = None;
+                    if targeted {
+                        (method.get_weight)(
+                            card.method_states[&method.name].clone(),
+                            card.difficult,
+                        )
                     } else {
-                        self.set.test_in_progress = false;
-                        self.set.reset_test();
+                        Ok(0.0)
                     }
-
-                    return Ok(None);
+                } else if targeted && !card.seen_in_test {
+                    Ok(1.0)
+                } else {
+                    Ok(0.0)
+                }
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let (card_id, card) = match WeightedIndex::new(&weights) {
+            Ok(dist) => cards_with_ids[dist.sample(&mut rng)],
+            // We're done!
+            Err(WeightedError::AllWeightsZero) => {
+                // If we've genuinely finished, say so
+                if self.method.is_some() {
+                    self.set.run_state = None;
+                } else {
+                    self.set.test_in_progress = false;
+                    self.set.reset_test();
                 }
-                Err(err) => return Err(Error::new(err)),
-            };
+
+                return Ok(None);
+            }
+            Err(err) => return Err(Error::new(err)),
+        };
 
         // Using a slim representation avoids potentially expensive cloning of the `Dynamic` data the method
         // maintains about this card
-        let slim = SlimCard {
-            question: card.question.clone(),
-            answer: card.answer.clone(),
-            starred: card.starred,
-            difficult: card.difficult,
-        };
+        let slim = card.to_slim();
 
-        self.latest_card = Some(**card_id);
+        self.latest_card = Some(*card_id);
         self.curr_count += 1;
 
         Ok(Some(slim))
@@ -238,17 +249,46 @@ impl<'e, 's> Driver<'e, 's> {
     /// you should call `.first()` instead, as calling this will lead to an error. Note that the provided response
     /// must be *identical* to one of the responses defined by the method in use (these can be found with `.allowed_responses()`).
     pub fn next(&mut self, response: String) -> Result<Option<SlimCard>> {
+        self.respond(response, None)
+    }
+    /// The same as `.next()`, but this also records how long the user took to respond to the last card in its review
+    /// history. It is up to the caller to decide exactly what this measures (e.g. the time between the question being
+    /// shown and the user asking for the answer).
+    pub fn next_with_latency(
+        &mut self,
+        response: String,
+        latency: Duration,
+    ) -> Result<Option<SlimCard>> {
+        self.respond(response, Some(latency))
+    }
+    /// Adjusts the last card based on the given response, recording it in the card's history, and gets the next card.
+    fn respond(&mut self, response: String, latency: Option<Duration>) -> Result<Option<SlimCard>> {
         if !self.allowed_responses().iter().any(|x| x == &response) {
             bail!("invalid user response to card");
         }
 
-        if let Some(card_id) = self.latest_card.as_mut() {
+        if let Some(card_id) = self.latest_card {
             // We know this element exists (we hold the only mutable reference to the set)
-            let card = self.set.cards.get_mut(card_id).unwrap();
+            let card = self.set.cards.get_mut(&card_id).unwrap();
+            let review = Review {
+                timestamp: seconds_since_epoch(),
+                mode: if self.method.is_some() {
+                    ReviewMode::Learn
+                } else {
+                    ReviewMode::Test
+                },
+                method: self.method.as_ref().map(|method| method.name.clone()),
+                response: response.clone(),
+                latency: latency.map(|latency| latency.as_millis() as u64),
+                id: Some(Uuid::new_v4()),
+            };
             if let Some(method) = &self.method {
-                let (method_data, difficult) =
-                    (method.adjust_card)(response, card.method_data.clone(), card.difficult)?;
-                card.method_data = method_data;
+                let (method_data, difficult) = (method.adjust_card)(
+                    response,
+                    card.method_states[&method.name].clone(),
+                    card.difficult,
+                )?;
+                card.method_states.insert(method.name.clone(), method_data);
                 if self.mutate_difficulty {
                     card.difficult = difficult;
                 }
@@ -264,6 +304,9 @@ impl<'e, 's> Driver<'e, 's> {
                 // Prevent this card from being double-adjusted if there's an error later
                 self.latest_card = None;
             }
+            // Only record the review once it's actually been applied
+            card.history.push(review);
+            self.last_adjusted = Some(card_id);
 
             // Everything has been adjusted
             self.first()
@@ -271,9 +314,45 @@ impl<'e, 's> Driver<'e, 's> {
             bail!("called `.next()` before `.first()`, or without handling error");
         }
     }
+    /// Produces a single-line record of the change made to the set by the last call to `.next()` (or `None` if no cards have been
+    /// reviewed yet). Rather than saving the whole set after every card, callers can append these records to a journal, which
+    /// is much faster for large sets, and then save the set once at the end of the session. If the session is interrupted
+    /// before the set can be saved, the journal can be replayed with [`Set::replay_journal`] to recover the user's progress.
+    ///
+    /// This should be called after every call to `.next()`, since each record only describes the latest card reviewed.
+    pub fn journal_entry(&self) -> Result<Option<String>> {
+        let card_id = match self.last_adjusted {
+            Some(card_id) => card_id,
+            None => return Ok(None),
+        };
+        // We know this element exists (we hold the only mutable reference to the set)
+        let card = &self.set.cards[&card_id];
+        let entry = JournalEntry {
+            id: card_id,
+            method_state: self
+                .method
+                .as_ref()
+                .map(|method| card.method_states[&method.name].clone()),
+            seen_in_test: card.seen_in_test,
+            difficult: card.difficult,
+            starred: card.starred,
+            // A review is always recorded before a card is marked as adjusted
+            review: card.history.last().unwrap().clone(),
+            method: self.set.method.clone(),
+            run_state: self.set.run_state.clone(),
+            test_in_progress: self.set.test_in_progress,
+        };
+
+        Ok(Some(serde_json::to_string(&entry)?))
+    }
     /// Saves the underlying set to JSON. This should generally be called between each presentation of a card to ensure the user
     /// does not lose their progress.
     pub fn save_set_to_json(&self) -> Result<String> {
         self.set.save()
     }
+    /// Saves the underlying set in the given format. Like `.save_set_to_json()`, this should generally be called between each
+    /// presentation of a card.
+    pub fn save_set_as(&self, format: SetFormat) -> Result<Vec<u8>> {
+        self.set.save_as(format)
+    }
 }
@@ -0,0 +1,95 @@
+use crate::set::Set;
+use anyhow::{Context, Result};
+use std::path::Path;
+
+/// The formats a set can be serialized in.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+pub enum SetFormat {
+    /// Human-readable JSON, which respects the set's [`crate::SaveOptions`]. This is the default.
+    #[default]
+    Json,
+    /// CBOR, a compact binary encoding, which is much smaller and faster to read and write than JSON for large sets, but
+    /// which can't be read by humans.
+    Cbor,
+}
+impl SetFormat {
+    /// Works out the format a set stored at the given path should be in from its extension. Files ending in `.cbor` are
+    /// assumed to be CBOR, and everything else is assumed to be JSON.
+    pub fn from_path(path: impl AsRef<Path>) -> Self {
+        match path.as_ref().extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("cbor") => Self::Cbor,
+            _ => Self::Json,
+        }
+    }
+}
+
+impl Set {
+    /// Saves this set in the given format, preserving all progress.
+    pub fn save_as(&self, format: SetFormat) -> Result<Vec<u8>> {
+        match format {
+            SetFormat::Json => Ok(self.save()?.into_bytes()),
+            SetFormat::Cbor => {
+                // Going through a JSON value keeps the layout identical to that of JSON sets (otherwise identifiers
+                // would be serialized as raw bytes), which means the same migrations work for both formats
+                let value = serde_json::to_value(self)?;
+                let mut bytes = Vec::new();
+                ciborium::into_writer(&value, &mut bytes)
+                    .with_context(|| "failed to serialize set to cbor")?;
+                Ok(bytes)
+            }
+        }
+    }
+    /// Loads this set from the given bytes, which should be in the given format. As with [`Self::from_json`], sets
+    /// serialized by older versions of Forne will be automatically upgraded to the current layout.
+    pub fn from_bytes(bytes: &[u8], format: SetFormat) -> Result<Self> {
+        match format {
+            SetFormat::Json => {
+                let json = std::str::from_utf8(bytes).with_context(|| "set json was not utf-8")?;
+                Self::from_json(json)
+            }
+            SetFormat::Cbor => {
+                let value: serde_json::Value = ciborium::from_reader(bytes)
+                    .with_context(|| "failed to deserialize set from cbor")?;
+                Self::from_value(value)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::set::tests::{card, set};
+    use rhai::Dynamic;
+    use uuid::Uuid;
+
+    #[test]
+    fn cbor_sets_round_trip() {
+        let mut card = card("Q", "A");
+        card.starred = true;
+        card.tags = vec!["tag".to_string()];
+        card.method_states
+            .insert("speed".to_string(), Dynamic::from_int(3));
+        let mut set = set([(Uuid::new_v4(), card)]);
+        set.exam_date = Some(1000);
+
+        let bytes = set.save_as(SetFormat::Cbor).unwrap();
+        let loaded = Set::from_bytes(&bytes, SetFormat::Cbor).unwrap();
+
+        assert_eq!(
+            serde_json::to_value(&loaded).unwrap(),
+            serde_json::to_value(&set).unwrap()
+        );
+        // The binary format should actually be smaller
+        assert!(bytes.len() < set.save().unwrap().len());
+    }
+
+    #[test]
+    fn formats_are_inferred_from_extensions() {
+        assert_eq!(SetFormat::from_path("set.cbor"), SetFormat::Cbor);
+        assert_eq!(SetFormat::from_path("set.CBOR"), SetFormat::Cbor);
+        assert_eq!(SetFormat::from_path("set.json"), SetFormat::Json);
+        assert_eq!(SetFormat::from_path("set"), SetFormat::Json);
+    }
+}
@@ -0,0 +1,154 @@
+use crate::set::{Review, Set};
+use anyhow::{Context, Result};
+use rhai::Dynamic;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// A single record in a session journal, describing what changed in the set when one card was reviewed. Only the parts
+/// of the card a review can change are recorded (not its content or its full history), so records stay small no matter
+/// how large the set or the card's history is.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct JournalEntry {
+    /// The unique identifier of the card that was reviewed.
+    pub(crate) id: Uuid,
+    /// The card's data for the method the set was being learned with after it was reviewed, or `None` if this was a test.
+    pub(crate) method_state: Option<Dynamic>,
+    pub(crate) seen_in_test: bool,
+    pub(crate) difficult: bool,
+    pub(crate) starred: bool,
+    /// The review that was added to the card's history.
+    pub(crate) review: Review,
+    /// The method the set was being learned with.
+    pub(crate) method: String,
+    /// The set's learn session state after the card was reviewed.
+    pub(crate) run_state: Option<String>,
+    /// Whether or not a test was in progress after the card was reviewed.
+    pub(crate) test_in_progress: bool,
+}
+
+impl Set {
+    /// Replays the given session journal on this set, bringing it up to date with any cards that were reviewed after it
+    /// was last saved. A journal is made up of the lines produced by [`crate::Driver::journal_entry`], and this should be
+    /// used to recover a session that crashed before its journal could be compacted into the set (by saving it as normal).
+    ///
+    /// If the last line of the journal is incomplete (e.g. because the program crashed while it was being written),
+    /// it will be ignored. Records for cards that are no longer in the set will also be ignored.
+    ///
+    /// This returns the number of records that were replayed.
+    pub fn replay_journal(&mut self, journal: &str) -> Result<usize> {
+        let lines = journal
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .collect::<Vec<_>>();
+        let mut num_replayed = 0;
+        for (idx, line) in lines.iter().enumerate() {
+            let entry = match serde_json::from_str::<JournalEntry>(line) {
+                Ok(entry) => entry,
+                Err(_) if idx == lines.len() - 1 => break,
+                Err(err) => {
+                    return Err(err).with_context(|| {
+                        format!("failed to parse record {} of session journal", idx + 1)
+                    })
+                }
+            };
+
+            let card = match self.cards.get_mut(&entry.id) {
+                Some(card) => card,
+                None => continue,
+            };
+            if let Some(method_state) = entry.method_state {
+                card.method_states
+                    .insert(entry.method.clone(), method_state);
+            }
+            card.seen_in_test = entry.seen_in_test;
+            card.difficult = entry.difficult;
+            card.starred = entry.starred;
+            // If the set was saved after this record was written, it will already have the review, and replaying should
+            // never duplicate it
+            if !card.history.contains(&entry.review) {
+                card.history.push(entry.review);
+            }
+            self.method = entry.method;
+            self.run_state = entry.run_state;
+            self.test_in_progress = entry.test_in_progress;
+            num_replayed += 1;
+        }
+
+        Ok(num_replayed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::set::{
+        tests::{card, set},
+        ReviewMode,
+    };
+
+    fn entry(id: Uuid, timestamp: i64) -> String {
+        let entry = JournalEntry {
+            id,
+            method_state: Some(Dynamic::from_int(timestamp)),
+            seen_in_test: false,
+            difficult: true,
+            starred: false,
+            review: Review {
+                timestamp,
+                mode: ReviewMode::Learn,
+                method: Some("speed".to_string()),
+                response: "y".to_string(),
+                latency: None,
+                id: Some(Uuid::new_v4()),
+            },
+            method: "speed".to_string(),
+            run_state: None,
+            test_in_progress: false,
+        };
+        serde_json::to_string(&entry).unwrap()
+    }
+
+    #[test]
+    fn records_are_replayed_once() {
+        let id = Uuid::new_v4();
+        let mut set = set([(id, card("Q", "A"))]);
+        let journal = [entry(id, 10), entry(id, 20)].join("\n");
+
+        assert_eq!(set.replay_journal(&journal).unwrap(), 2);
+        // Replaying a journal that has already been saved into the set shouldn't change anything
+        assert_eq!(set.replay_journal(&journal).unwrap(), 2);
+
+        let card = &set.cards[&id];
+        assert_eq!(card.history.len(), 2);
+        assert_eq!(card.method_states["speed"].as_int().unwrap(), 20);
+        assert!(card.difficult);
+    }
+
+    #[test]
+    fn truncated_last_records_are_ignored() {
+        let id = Uuid::new_v4();
+        let mut set = set([(id, card("Q", "A"))]);
+        let last = entry(id, 20);
+        let journal = format!("{}\n{}", entry(id, 10), &last[..last.len() / 2]);
+
+        assert_eq!(set.replay_journal(&journal).unwrap(), 1);
+        assert_eq!(set.cards[&id].history.len(), 1);
+    }
+
+    #[test]
+    fn corrupt_records_before_the_last_are_errors() {
+        let id = Uuid::new_v4();
+        let mut set = set([(id, card("Q", "A"))]);
+        let first = entry(id, 10);
+        let journal = format!("{}\n{}", &first[..first.len() / 2], entry(id, 20));
+
+        assert!(set.replay_journal(&journal).is_err());
+    }
+
+    #[test]
+    fn records_for_missing_cards_are_ignored() {
+        let mut set = set([(Uuid::new_v4(), card("Q", "A"))]);
+
+        assert_eq!(set.replay_journal(&entry(Uuid::new_v4(), 10)).unwrap(), 0);
+    }
+}
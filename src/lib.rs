@@ -2,12 +2,22 @@
 
 mod adapters;
 mod driver;
+mod format;
+mod journal;
 mod list;
+mod merge;
 mod methods;
+mod progress;
+mod query;
 mod set;
 
+pub use adapters::{CardUpdate, RemovalPolicy, UpdateReport, UpdateStatus};
 pub use driver::Driver;
+pub use format::SetFormat;
+pub use merge::{ConflictKind, MergeConflict, MergeReport};
 pub use methods::RawMethod;
+pub use progress::{ProgressKey, ProgressReport};
+pub use query::Query;
 pub use set::*;
 
 use anyhow::Result;
@@ -31,43 +41,73 @@ impl Forne {
     /// method, abstracting away the internal use of a Rhai engine. In general, you should prefer this method, as there is no additional
     /// overhead to using it.
     pub fn new_set(src: String, adapter_script: &str, raw_method: RawMethod) -> Result<Self> {
-        let engine = Self::create_engine();
+        let mut engine = Self::create_engine();
         let set = Set::new_with_adapter(src, adapter_script, raw_method, &engine)?;
+        Self::register_exam_date(&mut engine, set.exam_date);
 
         Ok(Self {
             set,
             rhai_engine: engine,
         })
     }
-    /// Updates the given set from a source. See [`Set::update_with_adapter`] for the exact behaviour of this method.
+    /// Updates the given set from a source, returning a report of what happened to each card. New cards will be added,
+    /// cards whose answers have changed will have their progress reset so the user can learn them again, and cards
+    /// that have only been reworded, or are unchanged, will keep their progress (as will archived cards that come back
+    /// into the source, which will be restored). Cards that are no longer in the source will be handled according to
+    /// the given [`RemovalPolicy`]. If the source contains the same card more than once, only the first will be used.
     pub fn update(
         &mut self,
         src: String,
         adapter_script: &str,
         raw_method: RawMethod,
-    ) -> Result<()> {
+        policy: RemovalPolicy,
+    ) -> Result<UpdateReport> {
         self.set
-            .update_with_adapter(adapter_script, src, raw_method, &self.rhai_engine)
+            .update_with_adapter(adapter_script, src, raw_method, policy, &self.rhai_engine)
+    }
+    /// Works out what updating the given set from a source would do, without changing the set at all. The report this
+    /// returns will be exactly what [`Self::update`] would, except that any new cards in it will be given fresh
+    /// identifiers, which have no meaning outside the report.
+    pub fn diff(&self, src: String, adapter_script: &str) -> Result<UpdateReport> {
+        self.set
+            .diff_with_adapter(adapter_script, src, &self.rhai_engine)
     }
     /// Creates a new Forne engine. While not inherently expensive, this should generally only be called once, or when
     /// the system needs to restart.
     pub fn from_set(set: Set) -> Self {
+        let mut engine = Self::create_engine();
+        Self::register_exam_date(&mut engine, set.exam_date);
+
         Self {
             set,
-            rhai_engine: Self::create_engine(),
+            rhai_engine: engine,
         }
     }
     /// Start a new learning session with this instance and the given method (see [`RawMethod`]), creating a [`Driver`]
     /// to run it.
     ///
-    /// # Errors
-    ///
-    /// This will return an error if the given method has not previously been used with this set, and a reset must be performed in that case,
-    /// which will lead to the loss of previous progress, unless a transformer is used.
+    /// If the given method has not previously been used with this set, every card will have its data for that method
+    /// initialised to the method's defaults. The data of any other methods previously used will be kept, so the user can
+    /// switch back to them later without losing their progress.
     pub fn learn(&mut self, raw_method: RawMethod) -> Result<Driver<'_, '_>> {
         let driver = Driver::new_learn(&mut self.set, raw_method, &self.rhai_engine)?;
         Ok(driver)
     }
+    /// Migrates all progress made with the method `from` on this set to the given method, using the transformer the given
+    /// method defines (its `transform_from` function). Any progress previously made with the given method will be
+    /// overwritten, but the progress made with `from` will be kept. Cards with no progress with `from`, or which the
+    /// transformer can't handle, will be given the method's default data.
+    ///
+    /// This returns the number of cards whose progress was transformed.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the given method does not define a transformer.
+    pub fn migrate(&mut self, from: &str, to: RawMethod) -> Result<usize> {
+        let parameters = self.set.method_parameters.get(to.name());
+        let method = to.into_method(parameters, &self.rhai_engine)?;
+        self.set.migrate_method(from, &method)
+    }
     /// Start a new test with this instance, creating a [`Driver`] to run it.
     pub fn test(&mut self) -> Driver<'_, '_> {
         Driver::new_test(&mut self.set)
@@ -81,13 +121,44 @@ impl Forne {
     pub fn save_set(&self) -> Result<String> {
         self.set.save()
     }
-    /// Resets all cards in a learn session back to the default metadata values prescribed by the learning method.
-    pub fn reset_learn(&mut self, method: RawMethod) -> Result<()> {
-        let method = method.into_method(&self.rhai_engine)?;
-        self.set.reset_learn((method.get_default_metadata)()?);
+    /// Saves this set in the given format. See [`Set::save_as`] for details.
+    pub fn save_set_as(&self, format: SetFormat) -> Result<Vec<u8>> {
+        self.set.save_as(format)
+    }
+    /// Records where this set's content and methods come from. See [`SetSources`] for details.
+    pub fn set_sources(&mut self, sources: SetSources) {
+        self.set.sources = sources;
+    }
+    /// Sets the date of the exam the user is studying this set for (in seconds since Unix epoch), or clears it. This will be
+    /// made available to learning methods through `get_exam_date()`.
+    pub fn set_exam_date(&mut self, exam_date: Option<i64>) {
+        self.set.exam_date = exam_date;
+        Self::register_exam_date(&mut self.rhai_engine, exam_date);
+    }
+    /// Applies progress saved from another copy of this set to it. See [`Set::apply_progress`] for details.
+    pub fn apply_progress(&mut self, progress: &str) -> Result<ProgressReport> {
+        let report = self.set.apply_progress(progress)?;
+        Self::register_exam_date(&mut self.rhai_engine, self.set.exam_date);
+        Ok(report)
+    }
+    /// Accepts the script of the given custom method as compatible with the data stored by the script it had when it was last
+    /// used with this set, even though it has changed. This should only be used if the changes don't affect how the method
+    /// stores its data: otherwise, the progress should be migrated to the new script with [`Self::migrate`] (migrating from
+    /// the method to itself).
+    pub fn accept_method_changes(&mut self, raw_method: RawMethod) -> Result<()> {
+        let parameters = self.set.method_parameters.get(raw_method.name());
+        let method = raw_method.into_method(parameters, &self.rhai_engine)?;
+        self.set.record_method(&method);
 
         Ok(())
     }
+    /// Resets all cards in a learn session back to the default metadata values prescribed by the learning method. Progress
+    /// made with other methods will not be affected.
+    pub fn reset_learn(&mut self, method: RawMethod) -> Result<()> {
+        let parameters = self.set.method_parameters.get(method.name());
+        let method = method.into_method(parameters, &self.rhai_engine)?;
+        self.set.reset_learn(&method)
+    }
     /// Resets all test progress for this set. This is irreversible!
     ///
     /// This will not change whether or not cards are starred.
@@ -169,15 +240,26 @@ impl Forne {
         // Support for working with timestamps
         engine.register_fn(
             "get_seconds_since_epoch", // Gets the number of *seconds* since Unix epoch
-            || {
-                match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
-                    Ok(duration) => duration.as_secs() as i64,
-                    // If we're before 01/01/1970...well ok then!
-                    Err(err) => -(err.duration().as_secs() as i64),
-                }
-            },
+            seconds_since_epoch,
         );
 
         engine
     }
+    /// Registers a function that gives scripts the given exam date (or `()` if there isn't one) on the given engine. This
+    /// has to be done separately from creating the engine, because the exam date belongs to the set.
+    fn register_exam_date(engine: &mut Engine, exam_date: Option<i64>) {
+        engine.register_fn("get_exam_date", move || match exam_date {
+            Some(exam_date) => Dynamic::from_int(exam_date),
+            None => Dynamic::UNIT,
+        });
+    }
+}
+
+/// Gets the number of *seconds* since Unix epoch.
+pub(crate) fn seconds_since_epoch() -> i64 {
+    match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        Ok(duration) => duration.as_secs() as i64,
+        // If we're before 01/01/1970...well ok then!
+        Err(err) => -(err.duration().as_secs() as i64),
+    }
 }
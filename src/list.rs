@@ -1,6 +1,6 @@
 use crate::{
     set::{CardType, Set},
-    SlimCard,
+    Query, SlimCard,
 };
 
 impl Set {
@@ -12,17 +12,17 @@ impl Set {
     pub fn list(&self, ty: CardType) -> Vec<SlimCard> {
         self.cards
             .values()
-            .filter(|card| {
-                ty == CardType::All
-                    || (ty == CardType::Difficult && card.difficult)
-                    || (ty == CardType::Starred && card.starred)
-            })
-            .map(|card| SlimCard {
-                question: card.question.to_string(),
-                answer: card.answer.to_string(),
-                difficult: card.difficult,
-                starred: card.starred,
-            })
+            .filter(|card| ty.matches(card))
+            .map(|card| card.to_slim())
+            .collect::<_>()
+    }
+    /// Lists all the terms in the set selected by the given query, returning them as pairs of questions and answers.
+    /// See [`Query`] for details of the query language.
+    pub fn list_query(&self, query: &Query) -> Vec<SlimCard> {
+        self.cards
+            .values()
+            .filter(|card| query.matches(card))
+            .map(|card| card.to_slim())
             .collect::<_>()
     }
 }
@@ -0,0 +1,414 @@
+use crate::adapters::CardIndex;
+use crate::set::{Card, Review, ReviewMode, Set};
+use std::collections::{HashMap, HashSet};
+use uuid::Uuid;
+
+/// The ways in which two copies of the same card can disagree in a way that merging can't resolve on its own.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictKind {
+    /// The question or answer of the card differs between the two sets. The content of whichever set was most recently
+    /// updated from its source has been kept (or that of the set being merged into, if that can't be worked out).
+    Content {
+        /// Whether or not the content of the other set was kept.
+        kept_other: bool,
+    },
+    /// Both copies of the card were reviewed independently since they were last the same, so their histories have been
+    /// combined, and the most recent progress for each method has been kept, but the progress made in the other copy
+    /// has effectively been lost.
+    DivergedHistory,
+}
+
+/// A conflict between two copies of the same card found while merging.
+#[derive(Clone, Debug)]
+pub struct MergeConflict {
+    /// The unique identifier of the card in both sets.
+    pub id: Uuid,
+    /// The question on the card, as it is in the merged set.
+    pub question: String,
+    /// The type of conflict.
+    pub kind: ConflictKind,
+}
+
+/// A report of what merging one set into another did.
+#[derive(Clone, Debug, Default)]
+pub struct MergeReport {
+    /// The number of cards that were only in the other set, and which have been added.
+    pub added: usize,
+    /// The number of cards that had more recent progress in the other set, and which have been updated with it.
+    pub updated: usize,
+    /// Any conflicts that were found between cards in the two sets.
+    pub conflicts: Vec<MergeConflict>,
+}
+
+impl Set {
+    /// Merges another copy of this set (e.g. one that has been studied on another device) into this one, card-by-card.
+    /// Cards are matched up by their unique identifiers, or, if the two sets were created separately from the same source,
+    /// in the same way as when a set is updated (by the identifiers their adapter gave them, or their questions). Any
+    /// cards that are only in the other set are added to this one.
+    ///
+    /// For cards in both sets, their review histories are combined, and, for each learning method, whichever copy of the
+    /// card was most recently reviewed with that method will have its progress kept. A card will be starred if it's starred
+    /// in either set. If the two copies of a card have different questions or answers, the content of whichever set was
+    /// most recently updated from its source will be kept (this set's, if neither records when it was), and the conflict
+    /// will be reported. Cards that were reviewed independently in both sets will be reported too, since one copy's
+    /// progress will have been overridden by the other's. Cards that have been archived in this set will stay archived,
+    /// but any progress made on them in the other set will still be merged into them.
+    ///
+    /// The state of any learn session or test in progress will be taken from whichever set was most recently reviewed.
+    pub fn merge(&mut self, other: Set) -> MergeReport {
+        let mut report = MergeReport::default();
+        let other_is_newer =
+            latest_review(other.cards.values()) > latest_review(self.cards.values());
+        let other_content_is_newer = other.last_updated > self.last_updated;
+
+        let matches = self.match_cards(&other);
+
+        for (their_id, their_card) in other.cards {
+            let id = match matches.get(&their_id) {
+                Some(id) => *id,
+                None => {
+                    self.cards.insert(their_id, their_card);
+                    report.added += 1;
+                    continue;
+                }
+            };
+            // If we've archived the card, it stays archived, but it keeps the progress made on it in the other set
+            let our_card = match self.cards.get_mut(&id) {
+                Some(card) => card,
+                None => self.archived.get_mut(&id).unwrap(),
+            };
+
+            if our_card.question != their_card.question || our_card.answer != their_card.answer {
+                if other_content_is_newer {
+                    our_card.question = their_card.question.clone();
+                    our_card.answer = their_card.answer.clone();
+                    our_card.source_id = their_card.source_id.clone();
+                    our_card.tags = their_card.tags.clone();
+                    our_card.hint = their_card.hint.clone();
+                    our_card.source_line = their_card.source_line;
+                    our_card.extra = their_card.extra.clone();
+                    our_card.source_position = their_card.source_position;
+                }
+                report.conflicts.push(MergeConflict {
+                    id,
+                    question: our_card.question.clone(),
+                    kind: ConflictKind::Content {
+                        kept_other: other_content_is_newer,
+                    },
+                });
+            }
+            let (updated, diverged) = merge_card(our_card, their_card);
+            if updated {
+                report.updated += 1;
+            }
+            if diverged {
+                report.conflicts.push(MergeConflict {
+                    id,
+                    question: our_card.question.clone(),
+                    kind: ConflictKind::DivergedHistory,
+                });
+            }
+        }
+        // Archived cards can't be reviewed, so we only need to add any we don't already have
+        for (id, their_card) in other.archived {
+            if !matches.contains_key(&id) {
+                self.archived.insert(id, their_card);
+            }
+        }
+
+        if other_content_is_newer {
+            self.last_updated = other.last_updated;
+        }
+        if other_is_newer {
+            self.method = other.method;
+            self.run_state = other.run_state;
+            self.test_in_progress = other.test_in_progress;
+        }
+
+        report
+    }
+    /// Works out which card in this set (active or archived) each card in the other set (active or archived) is another
+    /// copy of, returning a map of the other set's identifiers to ours. Cards that aren't in this set will be left out.
+    fn match_cards(&self, other: &Set) -> HashMap<Uuid, Uuid> {
+        let mut matches = HashMap::new();
+        let mut claimed = HashSet::new();
+        // Anything with the same identifier is definitely the same card, so no other card should take it over
+        for id in other.cards.keys().chain(other.archived.keys()) {
+            if self.cards.contains_key(id) || self.archived.contains_key(id) {
+                matches.insert(*id, *id);
+                claimed.insert(*id);
+            }
+        }
+        let index = CardIndex::new(self);
+        for (id, card) in other.cards.iter().chain(other.archived.iter()) {
+            if matches.contains_key(id) {
+                continue;
+            }
+            if let Some((our_id, _)) =
+                index.find(card.source_id.as_deref(), &card.question, &claimed)
+            {
+                matches.insert(*id, *our_id);
+                claimed.insert(*our_id);
+            }
+        }
+
+        matches
+    }
+}
+
+/// Merges the progress from the other copy of a card into ours, returning whether or not the other copy had any reviews
+/// ours didn't, and whether or not the two histories had diverged.
+fn merge_card(ours: &mut Card, theirs: Card) -> (bool, bool) {
+    // If one history is a prefix of the other, the copies haven't diverged
+    let common = ours
+        .history
+        .iter()
+        .zip(theirs.history.iter())
+        .take_while(|(a, b)| same_review(a, b))
+        .count();
+    let updated = common < theirs.history.len();
+    let diverged = updated && common < ours.history.len();
+
+    for (method, their_data) in theirs.method_states {
+        let ours_latest = latest_matching(&ours.history, |review| {
+            review.method.as_deref() == Some(&method)
+        });
+        let theirs_latest = latest_matching(&theirs.history, |review| {
+            review.method.as_deref() == Some(&method)
+        });
+        if !ours.method_states.contains_key(&method) || theirs_latest > ours_latest {
+            ours.method_states.insert(method, their_data);
+        }
+    }
+    // Difficulty is set by learn sessions, and whether or not the card has been seen by tests
+    if latest_matching(&theirs.history, |review| review.mode == ReviewMode::Learn)
+        > latest_matching(&ours.history, |review| review.mode == ReviewMode::Learn)
+    {
+        ours.difficult = theirs.difficult;
+    }
+    if latest_matching(&theirs.history, |review| review.mode == ReviewMode::Test)
+        > latest_matching(&ours.history, |review| review.mode == ReviewMode::Test)
+    {
+        ours.seen_in_test = theirs.seen_in_test;
+    }
+    ours.starred |= theirs.starred;
+
+    // Add any reviews we don't have, keeping everything in chronological order
+    for review in theirs.history.into_iter().skip(common) {
+        if !ours.history.iter().any(|ours| same_review(ours, &review)) {
+            ours.history.push(review);
+        }
+    }
+    ours.history.sort_by_key(|review| review.timestamp);
+
+    (updated, diverged)
+}
+
+/// Determines whether or not two reviews are records of the same response.
+fn same_review(a: &Review, b: &Review) -> bool {
+    match (a.id, b.id) {
+        (Some(a_id), Some(b_id)) => a_id == b_id,
+        // Reviews from older versions can only be compared by what they record, which can't tell apart identical
+        // responses given in the same second
+        _ => {
+            a.timestamp == b.timestamp
+                && a.mode == b.mode
+                && a.method == b.method
+                && a.response == b.response
+        }
+    }
+}
+
+/// Gets the time of the latest review in the given history that matches the given predicate.
+fn latest_matching(history: &[Review], predicate: impl Fn(&Review) -> bool) -> Option<i64> {
+    history
+        .iter()
+        .filter(|review| predicate(review))
+        .map(|review| review.timestamp)
+        .max()
+}
+
+/// Gets the time of the latest review of any of the given cards.
+fn latest_review<'a>(cards: impl Iterator<Item = &'a Card>) -> Option<i64> {
+    cards
+        .filter_map(|card| latest_matching(&card.history, |_| true))
+        .max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::set::tests::{card, set};
+    use rhai::Dynamic;
+
+    fn review(timestamp: i64, response: &str) -> Review {
+        Review {
+            timestamp,
+            mode: ReviewMode::Learn,
+            method: Some("speed".to_string()),
+            response: response.to_string(),
+            latency: None,
+            id: Some(Uuid::new_v4()),
+        }
+    }
+
+    /// Creates a card that has been reviewed with the given reviews, and has the given data for the `speed` method.
+    fn reviewed_card(history: Vec<Review>, weight: i64) -> Card {
+        let mut card = card("Q", "A");
+        card.history = history;
+        card.method_states
+            .insert("speed".to_string(), Dynamic::from_int(weight));
+        card
+    }
+
+    fn weight(card: &Card) -> i64 {
+        card.method_states["speed"].as_int().unwrap()
+    }
+
+    #[test]
+    fn newer_progress_is_taken_without_conflict() {
+        let id = Uuid::new_v4();
+        let first = review(10, "y");
+        let mut ours = set([(id, reviewed_card(vec![first.clone()], 1))]);
+        let theirs = set([(id, reviewed_card(vec![first, review(20, "n")], 2))]);
+
+        let report = ours.merge(theirs);
+
+        assert_eq!(report.updated, 1);
+        assert!(report.conflicts.is_empty());
+        assert_eq!(ours.cards[&id].history.len(), 2);
+        assert_eq!(weight(&ours.cards[&id]), 2);
+    }
+
+    #[test]
+    fn diverged_histories_are_combined_and_reported() {
+        let id = Uuid::new_v4();
+        let first = review(10, "y");
+        let mut ours = set([(id, reviewed_card(vec![first.clone(), review(30, "y")], 1))]);
+        let theirs = set([(id, reviewed_card(vec![first, review(20, "n")], 2))]);
+
+        let report = ours.merge(theirs);
+
+        assert_eq!(report.conflicts.len(), 1);
+        assert_eq!(report.conflicts[0].kind, ConflictKind::DivergedHistory);
+        let card = &ours.cards[&id];
+        assert_eq!(
+            card.history
+                .iter()
+                .map(|review| review.timestamp)
+                .collect::<Vec<_>>(),
+            vec![10, 20, 30]
+        );
+        // Our review was the most recent
+        assert_eq!(weight(card), 1);
+    }
+
+    #[test]
+    fn identical_responses_in_the_same_second_are_kept() {
+        let id = Uuid::new_v4();
+        let mut ours = set([(id, reviewed_card(vec![review(10, "y")], 1))]);
+        let theirs = set([(id, reviewed_card(vec![review(10, "y")], 1))]);
+
+        let report = ours.merge(theirs);
+
+        assert_eq!(report.conflicts.len(), 1);
+        assert_eq!(ours.cards[&id].history.len(), 2);
+    }
+
+    #[test]
+    fn reviews_from_older_versions_are_matched_by_content() {
+        let id = Uuid::new_v4();
+        let mut old_review = review(10, "y");
+        old_review.id = None;
+        let mut ours = set([(id, reviewed_card(vec![old_review.clone()], 1))]);
+        let theirs = set([(id, reviewed_card(vec![old_review], 1))]);
+
+        let report = ours.merge(theirs);
+
+        assert_eq!(report.updated, 0);
+        assert!(report.conflicts.is_empty());
+        assert_eq!(ours.cards[&id].history.len(), 1);
+    }
+
+    #[test]
+    fn content_is_taken_from_the_most_recently_updated_set() {
+        let id = Uuid::new_v4();
+        let mut ours = set([(id, card("Q", "old"))]);
+        ours.last_updated = Some(10);
+        let mut new_card = card("Q", "new");
+        new_card.tags = vec!["tag".to_string()];
+        let mut theirs = set([(id, new_card)]);
+        theirs.last_updated = Some(20);
+
+        let report = ours.merge(theirs);
+
+        assert_eq!(
+            report.conflicts[0].kind,
+            ConflictKind::Content { kept_other: true }
+        );
+        assert_eq!(ours.cards[&id].answer, "new");
+        assert_eq!(ours.cards[&id].tags, ["tag"]);
+        assert_eq!(ours.last_updated, Some(20));
+
+        let mut older = set([(id, card("Q", "old"))]);
+        older.last_updated = Some(5);
+        let report = ours.merge(older);
+
+        assert_eq!(
+            report.conflicts[0].kind,
+            ConflictKind::Content { kept_other: false }
+        );
+        assert_eq!(ours.cards[&id].answer, "new");
+    }
+
+    #[test]
+    fn cards_from_the_same_source_are_matched() {
+        let mut ours = set([(Uuid::new_v4(), card("Q", "A"))]);
+        let theirs = set([(Uuid::new_v4(), card("Q", "A"))]);
+
+        let report = ours.merge(theirs);
+
+        assert_eq!(report.added, 0);
+        assert_eq!(ours.cards.len(), 1);
+
+        let mut with_id = card("Q1", "A");
+        with_id.source_id = Some("a".to_string());
+        let mut ours = set([(Uuid::new_v4(), with_id.clone())]);
+        with_id.question = "Q2".to_string();
+        let theirs = set([(Uuid::new_v4(), with_id)]);
+
+        let report = ours.merge(theirs);
+
+        assert_eq!(report.added, 0);
+        assert_eq!(ours.cards.len(), 1);
+    }
+
+    #[test]
+    fn progress_is_merged_into_archived_cards() {
+        let id = Uuid::new_v4();
+        let first = review(10, "y");
+        let mut ours = set([]);
+        ours.archived
+            .insert(id, reviewed_card(vec![first.clone()], 1));
+        let theirs = set([(id, reviewed_card(vec![first, review(20, "n")], 2))]);
+
+        let report = ours.merge(theirs);
+
+        assert_eq!(report.updated, 1);
+        assert!(ours.cards.is_empty());
+        assert_eq!(ours.archived[&id].history.len(), 2);
+        assert_eq!(weight(&ours.archived[&id]), 2);
+    }
+
+    #[test]
+    fn new_cards_are_added() {
+        let (ours_id, theirs_id) = (Uuid::new_v4(), Uuid::new_v4());
+        let mut ours = set([(ours_id, card("Q1", "A1"))]);
+        let theirs = set([(theirs_id, card("Q2", "A2"))]);
+
+        let report = ours.merge(theirs);
+
+        assert_eq!(report.added, 1);
+        assert_eq!(ours.cards.len(), 2);
+    }
+}
@@ -1,6 +1,9 @@
+use crate::set::MethodFingerprint;
 use anyhow::{anyhow, bail, Context, Result};
 use include_dir::{include_dir, Dir};
-use rhai::{Array, Dynamic, Engine, Scope, AST};
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope, AST};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
 
 /// The `src/methods` directory that includes this file.
 static METHODS: Dir<'_> = include_dir!("$CARGO_MANIFEST_DIR/src/methods");
@@ -33,6 +36,18 @@ pub struct Method<'e> {
     /// this method to initialise all its cards with metadata that is appropriate to this method. Generally,
     /// methods should keep this as small as possible to minimise the size of sets on-disk.
     pub get_default_metadata: Box<dyn Fn() -> Result<Dynamic> + Send + Sync + 'e>,
+    /// A closure that, given the name of another method, a card's metadata from that method, and whether or not the card
+    /// has been marked as difficult, converts that metadata into metadata for this method. This will be `None` if the
+    /// method script doesn't define a `transform_from` function.
+    ///
+    /// The closure will return `None` if the method doesn't know how to transform metadata from the given method, in which
+    /// case the card should be given the default metadata for this method instead.
+    #[allow(clippy::type_complexity)]
+    pub transform_from:
+        Option<Box<dyn Fn(String, Dynamic, bool) -> Result<Option<Dynamic>> + Send + Sync + 'e>>,
+    /// A fingerprint of the script that defines this method, which is used to detect when it changes. This will be `None`
+    /// for inbuilt methods.
+    pub fingerprint: Option<MethodFingerprint>,
 }
 impl<'e> Method<'e> {
     /// Compiles the given inbuilt script into a full-fledged [`Method`].
@@ -45,7 +60,11 @@ impl<'e> Method<'e> {
     ///
     /// This will panic if compilation fails, as compilation should never fail for an inbuilt method, and this would represent
     /// a bug in Forne.
-    fn from_inbuilt(method_name: &str, engine: &'e Engine) -> Result<Self> {
+    fn from_inbuilt(
+        method_name: &str,
+        parameters: Option<&BTreeMap<String, Dynamic>>,
+        engine: &'e Engine,
+    ) -> Result<Self> {
         if !Method::is_inbuilt(method_name) {
             bail!("provided method name '{method_name}' is not an inbuilt method (are you using the latest version of forne?)");
         }
@@ -57,7 +76,7 @@ impl<'e> Method<'e> {
         let ast = engine
             .compile(script)
             .expect("inbuilt method should not panic on compilation (this is a bug in forne!)");
-        let method = Self::from_ast(method_name, ast, engine)?;
+        let method = Self::from_ast(method_name, ast, parameters, engine)?;
 
         Ok(method)
     }
@@ -67,11 +86,27 @@ impl<'e> Method<'e> {
     ///
     /// This will return an error if compiling the provided script fails, or if it does not contain the required elements. See the documentation
     /// of custom methods for details of what these elements are.
-    fn from_custom(method_name: &str, method_script: &str, engine: &'e Engine) -> Result<Self> {
+    fn from_custom(
+        method_name: &str,
+        method_script: &str,
+        parameters: Option<&BTreeMap<String, Dynamic>>,
+        engine: &'e Engine,
+    ) -> Result<Self> {
         let ast = engine
             .compile(method_script)
             .with_context(|| "compiling custom method script failed")?;
-        let method = Self::from_ast(method_name, ast, engine)?;
+        // Scripts can optionally declare a version, which should only change when the way they store data does
+        let version = ast
+            .iter_literal_variables(true, false)
+            .find(|(name, _, _)| *name == "VERSION")
+            .map(|(_, _, version)| version.to_string());
+        let fingerprint = MethodFingerprint {
+            hash: format!("{:x}", Sha256::digest(method_script)),
+            version,
+        };
+
+        let mut method = Self::from_ast(method_name, ast, parameters, engine)?;
+        method.fingerprint = Some(fingerprint);
 
         Ok(method)
     }
@@ -81,43 +116,97 @@ impl<'e> Method<'e> {
     ///
     /// This will explicitly fail if it cannot find the `const RESPONSES` array in the provided AST, but it will create closures that
     /// produce errors when executed if the AST does not contain the required functions `get_weight` and `adjust_card`, or if they
-    /// are invalid in some way.
-    fn from_ast(method_name: &str, ast: AST, engine: &'e Engine) -> Result<Self> {
+    /// are invalid in some way. The optional `transform_from` function will only be extracted if the AST defines it.
+    ///
+    /// If the method declares parameters (in a `const PARAMETERS` map), the given overrides will be applied to their defaults,
+    /// and the result will be passed to each of the method's functions that takes an extra argument for them. This will
+    /// fail if any of the overrides are for parameters the method doesn't declare, or have a different type to their
+    /// defaults.
+    fn from_ast(
+        method_name: &str,
+        ast: AST,
+        overrides: Option<&BTreeMap<String, Dynamic>>,
+        engine: &'e Engine,
+    ) -> Result<Self> {
+        // Iterate through all literal constants and find `RESPONSES` and `PARAMETERS`
+        let mut responses = None;
+        let mut parameters = Map::new();
+        for (name, _, value) in ast.iter_literal_variables(true, false) {
+            if name == "RESPONSES" {
+                let value = value.into_typed_array().map_err(|_| anyhow!("required constant `RESPONSES` in method script was not an array of strings"))?;
+                responses = Some(value);
+            } else if name == "PARAMETERS" {
+                parameters = value.try_cast::<Map>().ok_or(anyhow!(
+                    "constant `PARAMETERS` in method script was not an object map"
+                ))?;
+            }
+        }
+        for (name, value) in overrides.into_iter().flatten() {
+            let default = parameters.get_mut(name.as_str()).ok_or_else(|| {
+                anyhow!("method '{method_name}' does not have a parameter called '{name}'")
+            })?;
+            *default = Self::coerce_parameter(name, value.clone(), default)?;
+        }
+        // Methods written before parameters existed won't expect them, so we only pass them to functions that take an
+        // extra argument for them
+        let parameters_for = |fn_name: &str, num_args: usize| {
+            ast.iter_functions()
+                .any(|func| func.name == fn_name && func.params.len() == num_args + 1)
+                .then(|| Dynamic::from_map(parameters.clone()))
+        };
+        let weight_parameters = parameters_for("get_weight", 2);
+        let adjust_parameters = parameters_for("adjust_card", 3);
+        let default_parameters = parameters_for("get_default_metadata", 0);
+        let transform_parameters = parameters_for("transform_from", 3);
+
         // Extract the closures directly (using the shared engine)
         let ast1 = ast.clone();
         let ast2 = ast.clone();
         let ast3 = ast.clone();
-        let get_weight = Box::new(move |method_data, difficult| {
+        let ast4 = ast.clone();
+        let get_weight = Box::new(move |method_data, difficult: bool| {
+            let mut args = vec![method_data, difficult.into()];
+            args.extend(weight_parameters.clone());
             engine
-                .call_fn(
-                    &mut Scope::new(),
-                    &ast,
-                    "get_weight",
-                    (method_data, difficult),
-                )
-                .with_context(|| {
-                    "failed to get weight for card (this is a bug in the selected learning method)"
+                .call_fn(&mut Scope::new(), &ast, "get_weight", args)
+                .map_err(|err| {
+                    method_error(
+                        *err,
+                        "failed to get weight for card (this is a bug in the selected learning method)",
+                    )
                 })
         });
-        let adjust_card = Box::new(move |res, method_data, difficult| {
-            let res: Array = engine.call_fn(&mut Scope::new(), &ast1, "adjust_card", (res, method_data, difficult)).with_context(|| "failed to adjust card data for last card (this is a bug in the selected learning method)")?;
-            let method_data = res.get(0).ok_or(anyhow!("no method data provided from card adjustment (this is a bug in the selected learning method)"))?;
+        let adjust_card = Box::new(move |res: String, method_data, difficult: bool| {
+            let mut args = vec![res.into(), method_data, difficult.into()];
+            args.extend(adjust_parameters.clone());
+            let res: Array = engine.call_fn(&mut Scope::new(), &ast1, "adjust_card", args).map_err(|err| method_error(*err, "failed to adjust card data for last card (this is a bug in the selected learning method)"))?;
+            let method_data = res.first().ok_or(anyhow!("no method data provided from card adjustment (this is a bug in the selected learning method)"))?;
             let difficult = res.get(1).ok_or(anyhow!("no difficulty boolean provided from card adjustment (this is a bug in the selected learning method)"))?.as_bool().map_err(|_| anyhow!("invalid difficulty boolean provided from card adjustment (this is a bug in the selected learning method)"))?;
 
             Ok((method_data.clone(), difficult))
         });
         let get_default_metadata = Box::new(move || {
-            engine.call_fn(&mut Scope::new(), &ast2, "get_default_metadata", ()).with_context(|| "failed to get default metadata for a new card (this is a bug in the selected learning method)")
+            let args = Vec::from_iter(default_parameters.clone());
+            engine.call_fn(&mut Scope::new(), &ast2, "get_default_metadata", args).with_context(|| "failed to get default metadata for a new card (this is a bug in the selected learning method)")
         });
 
-        // Iterate through all literal constants and find `RESPONSES`
-        let mut responses = None;
-        for (name, _, value) in ast3.iter_literal_variables(true, false) {
-            if name == "RESPONSES" {
-                let value = value.into_typed_array().map_err(|_| anyhow!("required constant `RESPONSES` in method script was not an array of strings"))?;
-                responses = Some(value);
-            }
-        }
+        let transform_from = if ast3
+            .iter_functions()
+            .any(|func| func.name == "transform_from" && (3..=4).contains(&func.params.len()))
+        {
+            let transform_from: Box<
+                dyn Fn(String, Dynamic, bool) -> Result<Option<Dynamic>> + Send + Sync + 'e,
+            > = Box::new(move |old_method: String, old_data, difficult: bool| {
+                let mut args = vec![old_method.into(), old_data, difficult.into()];
+                args.extend(transform_parameters.clone());
+                let method_data: Dynamic = engine.call_fn(&mut Scope::new(), &ast4, "transform_from", args).with_context(|| "failed to transform card data from previous method (this is a bug in the selected learning method)")?;
+                // Methods return `()` if they can't handle the old method
+                Ok((!method_data.is_unit()).then_some(method_data))
+            });
+            Some(transform_from)
+        } else {
+            None
+        };
 
         if let Some(responses) = responses {
             // Assemble all that into a method
@@ -127,11 +216,48 @@ impl<'e> Method<'e> {
                 get_weight,
                 adjust_card,
                 get_default_metadata,
+                transform_from,
+                fingerprint: None,
             })
         } else {
             bail!("method script did not define required constant `RESPONSES`");
         }
     }
+    /// Checks that the given override for a parameter has the same type as its default, converting integers to floats if
+    /// that's what the default is (so users don't have to write `3.0` rather than `3`).
+    fn coerce_parameter(name: &str, value: Dynamic, default: &Dynamic) -> Result<Dynamic> {
+        if default.is_float() && value.is_int() {
+            Ok(Dynamic::from_float(value.as_int().unwrap() as f64))
+        } else if value.type_name() != default.type_name() {
+            bail!(
+                "parameter '{name}' should be of type '{}', but a value of type '{}' was given",
+                default.type_name(),
+                value.type_name()
+            );
+        } else {
+            Ok(value)
+        }
+    }
+    /// Produces the initial metadata for a card that is being given data for this method for the first time. If the card
+    /// has data from a previous method and this method can transform it, that will be used, otherwise this method's
+    /// default metadata will be used.
+    pub(crate) fn initial_metadata(
+        &self,
+        previous: Option<(&str, &Dynamic)>,
+        difficult: bool,
+    ) -> Result<Dynamic> {
+        if let (Some(transform_from), Some((old_method, old_data))) =
+            (&self.transform_from, previous)
+        {
+            if let Some(method_data) =
+                transform_from(old_method.to_string(), old_data.clone(), difficult)?
+            {
+                return Ok(method_data);
+            }
+        }
+
+        (self.get_default_metadata)()
+    }
     /// Determines if the given method name is inbuilt. This may be unwittingly provided a full method script as well.
     fn is_inbuilt(method: &str) -> bool {
         METHODS.files().any(|file| {
@@ -140,6 +266,17 @@ impl<'e> Method<'e> {
     }
 }
 
+/// Converts an error from calling one of a method's functions into an [`anyhow::Error`] with the given context, unless the
+/// method threw it deliberately (e.g. because the set isn't set up the way the method needs), in which case the message
+/// it threw is used alone, since it's meant for the user.
+fn method_error(err: EvalAltResult, context: &'static str) -> anyhow::Error {
+    match err {
+        EvalAltResult::ErrorRuntime(value, _) => anyhow!("{}", value),
+        EvalAltResult::ErrorInFunctionCall(_, _, err, _) => method_error(*err, context),
+        err => anyhow::Error::new(err).context(context),
+    }
+}
+
 /// A representation of a method that has not yet been created.
 #[derive(Clone, Debug)]
 pub enum RawMethod {
@@ -156,6 +293,10 @@ pub enum RawMethod {
         /// the metadata Alice's script expected to Chloe's, at best causing it to completely fail, and at worst causing all Bob's previous
         /// data to be overwritten irretrievably. This could be avoided if Alice produced `alice/powerlearn-v2` and Chloe produces
         /// `chloe/powerlearn-v2`.
+        ///
+        /// To catch cases like this, sets record a fingerprint of the script each custom method had when it was last used
+        /// with them (see [`crate::MethodFingerprint`]), and will refuse to be learned with a different script of the same name
+        /// unless its progress is migrated, or the new script is explicitly accepted.
         name: String,
         /// The body of the Rhai script that defines this method, which must contain several key elements (see the documentation of custom
         /// methods to learn more about these).
@@ -163,16 +304,28 @@ pub enum RawMethod {
     },
 }
 impl RawMethod {
-    /// Converts this raw method into a fully-fledged [`Method`].
+    /// Gets the name of this method.
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Inbuilt(name) => name,
+            Self::Custom { name, .. } => name,
+        }
+    }
+    /// Converts this raw method into a fully-fledged [`Method`], overriding the defaults of any of the parameters it declares
+    /// with the given values (generally those stored in the set for this method).
     ///
     /// # Panics
     ///
     /// This will panic if compiling an inbuilt method fails, as this would be a bug in Forne. Any other failure will be
     /// gracefully returned as an error.
-    pub(crate) fn into_method(self, engine: &Engine) -> Result<Method<'_>> {
+    pub(crate) fn into_method<'e>(
+        self,
+        parameters: Option<&BTreeMap<String, Dynamic>>,
+        engine: &'e Engine,
+    ) -> Result<Method<'e>> {
         match self {
-            Self::Inbuilt(name) => Method::from_inbuilt(&name, engine),
-            Self::Custom { name, body } => Method::from_custom(&name, &body, engine),
+            Self::Inbuilt(name) => Method::from_inbuilt(&name, parameters, engine),
+            Self::Custom { name, body } => Method::from_custom(&name, &body, parameters, engine),
         }
     }
     /// Determines whether or not the given method name or script is inbuilt. This can be used in situations of ambiguity, such
@@ -0,0 +1,325 @@
+use crate::set::{Card, MethodFingerprint, Review, Set, SetSources, SET_VERSION};
+use anyhow::{bail, Result};
+use rhai::Dynamic;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+/// All the progress the user has made on a set, without any of its content. This is what's stored in a separate progress
+/// file when a set is split (see [`Set::save_progress`]).
+#[derive(Serialize, Deserialize)]
+struct Progress {
+    /// The version of the set layout this progress was saved from.
+    version: u32,
+    /// The name of the method most recently used to learn the set.
+    method: String,
+    /// The set's learn session state.
+    run_state: Option<String>,
+    /// Whether or not a test is in progress.
+    test_in_progress: bool,
+    /// The progress on each card, keyed by the card's unique identifier. This includes archived cards.
+    cards: HashMap<Uuid, CardProgress>,
+    /// The date of the exam the user is studying the set for, if they've set one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exam_date: Option<i64>,
+    /// The parameters the user has changed for the methods used on the set.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    method_parameters: BTreeMap<String, BTreeMap<String, Dynamic>>,
+    /// The fingerprints of the custom methods the card progress was made with.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    method_fingerprints: BTreeMap<String, MethodFingerprint>,
+    /// Where the set's source, adapter, and custom methods are on the user's computer. These are specific to the user,
+    /// so they're kept with the progress, but they're only used when the set is joined back together.
+    #[serde(default)]
+    sources: SetSources,
+}
+
+/// The key used to find a card's progress again if the set's content is re-created from its source, in which case the card
+/// will have a new identifier.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProgressKey {
+    /// The identifier the adapter gave the card.
+    SourceId(String),
+    /// The card's question, for cards the adapter didn't give an identifier.
+    Question(String),
+}
+impl ProgressKey {
+    /// Gets the key a card will be matched with progress by if it isn't matched by identifier.
+    fn for_card(card: &Card) -> Self {
+        match &card.source_id {
+            Some(source_id) => Self::SourceId(source_id.clone()),
+            None => Self::Question(card.question.clone()),
+        }
+    }
+}
+
+/// A report of what applying progress to a set did.
+#[derive(Clone, Debug, Default)]
+pub struct ProgressReport {
+    /// The number of cards the progress was applied to.
+    pub applied: usize,
+    /// Any keys that progress couldn't be matched by, because more than one card in the set (or more than one card in
+    /// the progress) has them. The progress for these hasn't been applied to any card.
+    pub duplicates: Vec<ProgressKey>,
+}
+
+/// The progress the user has made on a single card.
+#[derive(Serialize, Deserialize)]
+struct CardProgress {
+    /// The key used to find this card again if it has a new identifier.
+    key: ProgressKey,
+    seen_in_test: bool,
+    difficult: bool,
+    starred: bool,
+    method_states: BTreeMap<String, Dynamic>,
+    history: Vec<Review>,
+}
+impl Set {
+    /// Saves only the content of this set to JSON, without any progress. Like the progress saved by
+    /// [`Self::save_progress`], this can be shared with others or kept in version control, and it can be loaded as a set
+    /// in its own right (as though it had never been studied). Anything specific to the user, like where the set's source
+    /// is on their computer, the date of their exam, and the parameters they've set for their methods, is kept with the
+    /// progress instead.
+    pub fn save_content(&self) -> Result<String> {
+        let strip = |cards: &HashMap<Uuid, Card>| {
+            cards
+                .iter()
+                .map(|(id, card)| {
+                    let mut card = card.clone();
+                    card.seen_in_test = false;
+                    card.difficult = false;
+                    card.starred = false;
+                    card.method_states = BTreeMap::new();
+                    card.history = Vec::new();
+                    (*id, card)
+                })
+                .collect()
+        };
+        let content = Set {
+            version: self.version,
+            method: self.method.clone(),
+            cards: strip(&self.cards),
+            archived: strip(&self.archived),
+            save_options: self.save_options,
+            sources: SetSources::default(),
+            last_updated: self.last_updated,
+            exam_date: None,
+            method_parameters: BTreeMap::new(),
+            method_fingerprints: BTreeMap::new(),
+            run_state: None,
+            test_in_progress: false,
+        };
+
+        content.save()
+    }
+    /// Saves only the progress the user has made on this set to JSON, keyed by card. This can be applied to a set with the
+    /// same content with [`Self::apply_progress`], including one created afresh from the same source.
+    pub fn save_progress(&self) -> Result<String> {
+        let progress = Progress {
+            version: SET_VERSION,
+            method: self.method.clone(),
+            run_state: self.run_state.clone(),
+            test_in_progress: self.test_in_progress,
+            exam_date: self.exam_date,
+            cards: self
+                .cards
+                .iter()
+                .chain(self.archived.iter())
+                .map(|(id, card)| {
+                    let progress = CardProgress {
+                        key: ProgressKey::for_card(card),
+                        seen_in_test: card.seen_in_test,
+                        difficult: card.difficult,
+                        starred: card.starred,
+                        method_states: card.method_states.clone(),
+                        history: card.history.clone(),
+                    };
+                    (*id, progress)
+                })
+                .collect(),
+            method_parameters: self.method_parameters.clone(),
+            method_fingerprints: self.method_fingerprints.clone(),
+            sources: self.sources.clone(),
+        };
+
+        let json = if self.save_options.pretty {
+            serde_json::to_string_pretty(&progress)?
+        } else {
+            serde_json::to_string(&progress)?
+        };
+        Ok(json)
+    }
+    /// Applies the given progress (from [`Self::save_progress`]) to this set, replacing any progress it already has on the
+    /// cards the progress covers. Cards are matched by their unique identifiers, or, if this set was created afresh from
+    /// the same source, by the identifiers the adapter gave them or their questions.
+    ///
+    /// If more than one card could be matched with the same progress, it won't be applied to any of them, and the
+    /// conflicting keys will be reported. Any exam date recorded in the progress will replace this set's, but any sources
+    /// recorded in it will be ignored, since this set will have its own.
+    pub fn apply_progress(&mut self, json: &str) -> Result<ProgressReport> {
+        let progress: Progress = serde_json::from_str(json)?;
+        self.apply(progress)
+    }
+    /// Applies the given deserialized progress to this set (see [`Self::apply_progress`]).
+    fn apply(&mut self, progress: Progress) -> Result<ProgressReport> {
+        if progress.version > SET_VERSION {
+            bail!("this progress was saved by a newer version of forne (its version is {}, but this version of forne only supports up to {SET_VERSION}), please upgrade forne to use it", progress.version);
+        }
+
+        let mut keys = HashMap::<_, Vec<_>>::new();
+        for (id, card) in self.cards.iter().chain(self.archived.iter()) {
+            keys.entry(ProgressKey::for_card(card))
+                .or_default()
+                .push(*id);
+        }
+        // Anything that can't be matched by identifier has to be matched by key, which only works if there's exactly one
+        // card with that key on either side
+        let mut matched = Vec::new();
+        let mut by_key = HashMap::<_, Vec<_>>::new();
+        for (id, card_progress) in progress.cards {
+            if self.cards.contains_key(&id) || self.archived.contains_key(&id) {
+                matched.push((id, card_progress));
+            } else {
+                by_key
+                    .entry(card_progress.key.clone())
+                    .or_default()
+                    .push(card_progress);
+            }
+        }
+        let mut report = ProgressReport::default();
+        for (key, mut card_progresses) in by_key {
+            match keys.get(&key).map(Vec::as_slice) {
+                Some([id]) if card_progresses.len() == 1 => {
+                    matched.push((*id, card_progresses.pop().unwrap()))
+                }
+                Some(_) => report.duplicates.push(key),
+                None => continue,
+            }
+        }
+        report.duplicates.sort();
+
+        for (id, card_progress) in matched {
+            // We know the card is in one of these
+            let card = match self.cards.get_mut(&id) {
+                Some(card) => card,
+                None => self.archived.get_mut(&id).unwrap(),
+            };
+
+            card.seen_in_test = card_progress.seen_in_test;
+            card.difficult = card_progress.difficult;
+            card.starred = card_progress.starred;
+            card.method_states = card_progress.method_states;
+            card.history = card_progress.history;
+            report.applied += 1;
+        }
+        self.method = progress.method;
+        self.run_state = progress.run_state;
+        self.test_in_progress = progress.test_in_progress;
+        if progress.exam_date.is_some() {
+            self.exam_date = progress.exam_date;
+        }
+        self.method_parameters.extend(progress.method_parameters);
+        self.method_fingerprints
+            .extend(progress.method_fingerprints);
+
+        Ok(report)
+    }
+    /// Loads a set that has been split into its content and progress (see [`Self::save_content`] and
+    /// [`Self::save_progress`]), recombining them. The set's sources will be those recorded in the progress.
+    pub fn from_split(content: &str, progress: &str) -> Result<Self> {
+        let mut set = Self::from_json(content)?;
+        let mut progress: Progress = serde_json::from_str(progress)?;
+        set.sources = std::mem::take(&mut progress.sources);
+        set.apply(progress)?;
+        Ok(set)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::set::tests::{card, set};
+
+    /// Re-creates the given set's content with new identifiers, as though it had been created afresh from its source.
+    fn recreate(set: &Set) -> Set {
+        let mut recreated = Set::from_json(&set.save_content().unwrap()).unwrap();
+        recreated.cards = std::mem::take(&mut recreated.cards)
+            .into_values()
+            .map(|card| (Uuid::new_v4(), card))
+            .collect();
+        recreated
+    }
+
+    #[test]
+    fn split_and_join_round_trip() {
+        let mut starred = card("Q1", "A1");
+        starred.starred = true;
+        let mut original = set([
+            (Uuid::new_v4(), starred),
+            (Uuid::new_v4(), card("Q2", "A2")),
+        ]);
+        original.sources.source = Some("source.md".into());
+        original.exam_date = Some(1000);
+
+        let joined = Set::from_split(
+            &original.save_content().unwrap(),
+            &original.save_progress().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(joined.cards.len(), 2);
+        for (id, card) in original.cards.iter() {
+            assert_eq!(joined.cards[id].starred, card.starred);
+        }
+        assert_eq!(joined.sources.source, original.sources.source);
+        assert_eq!(joined.exam_date, original.exam_date);
+    }
+
+    #[test]
+    fn content_leaves_out_progress() {
+        let mut starred = card("Q", "A");
+        starred.starred = true;
+        let mut original = set([(Uuid::new_v4(), starred)]);
+        original.exam_date = Some(1000);
+
+        let content = Set::from_json(&original.save_content().unwrap()).unwrap();
+        assert!(content.cards.values().all(|card| !card.starred));
+        assert_eq!(content.exam_date, None);
+    }
+
+    #[test]
+    fn keeps_identifiers_and_questions_apart() {
+        let mut with_id = card("Q", "A1");
+        with_id.source_id = Some("foo".to_string());
+        with_id.starred = true;
+        let mut with_question = card("foo", "A2");
+        with_question.difficult = true;
+        let original = set([(Uuid::new_v4(), with_id), (Uuid::new_v4(), with_question)]);
+
+        let mut recreated = recreate(&original);
+        let report = recreated
+            .apply_progress(&original.save_progress().unwrap())
+            .unwrap();
+        assert_eq!(report.applied, 2);
+        assert!(report.duplicates.is_empty());
+        for card in recreated.cards.values() {
+            assert_eq!(card.starred, card.source_id.is_some());
+            assert_eq!(card.difficult, card.source_id.is_none());
+        }
+    }
+
+    #[test]
+    fn reports_duplicate_keys() {
+        let mut starred = card("Q", "A1");
+        starred.starred = true;
+        let original = set([(Uuid::new_v4(), starred), (Uuid::new_v4(), card("Q", "A2"))]);
+
+        let mut recreated = recreate(&original);
+        let report = recreated
+            .apply_progress(&original.save_progress().unwrap())
+            .unwrap();
+        assert_eq!(report.applied, 0);
+        assert_eq!(report.duplicates, [ProgressKey::Question("Q".to_string())]);
+        assert!(recreated.cards.values().all(|card| !card.starred));
+    }
+}
@@ -0,0 +1,338 @@
+use crate::set::{Card, CardType};
+use anyhow::{anyhow, bail, Context, Result};
+use fancy_regex::Regex;
+use std::str::FromStr;
+
+/// A filter for selecting cards from a set, parsed from a small boolean query language. This can be used to build
+/// study subsets that are too specific to express with a single [`CardType`].
+///
+/// Queries are made up of the following terms:
+///
+/// - `all`, `starred`, and `difficult`, which select the same cards as the corresponding [`CardType`]s
+/// - `tag:<tag>`, which selects cards with the given tag (hierarchically, as in [`CardType::Tagged`])
+/// - `question:<regex>` and `answer:<regex>`, which select cards whose question or answer matches the given regular
+///   expression (this can be surrounded in double quotes if it contains spaces or parentheses)
+///
+/// These can be combined with `and`, `or`, and `not` (case-insensitive), and grouped with parentheses. `not` binds
+/// most tightly, followed by `and`, and then `or`, so `starred and tag:verbs and not difficult` and
+/// `(tag:nouns or tag:verbs) and question:"^to "` are both valid queries.
+#[derive(Clone, Debug)]
+pub struct Query {
+    /// The root of the parsed expression tree.
+    expr: Expr,
+}
+impl Query {
+    /// Parses the given query string.
+    pub fn parse(query: &str) -> Result<Self> {
+        let tokens = tokenize(query)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let expr = parser.parse_or()?;
+        if let Some(token) = parser.tokens.get(parser.pos) {
+            bail!("unexpected token '{}' in query", token.as_str());
+        }
+
+        Ok(Self { expr })
+    }
+    /// Combines this query with another, producing a query that selects only the cards selected by both.
+    pub fn and(self, other: Query) -> Self {
+        Self {
+            expr: Expr::And(Box::new(self.expr), Box::new(other.expr)),
+        }
+    }
+    /// Determines whether or not the given card is selected by this query.
+    pub(crate) fn matches(&self, card: &Card) -> bool {
+        self.expr.matches(card)
+    }
+}
+impl FromStr for Query {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Self::parse(s)
+    }
+}
+impl From<CardType> for Query {
+    fn from(ty: CardType) -> Self {
+        Self {
+            expr: Expr::Type(ty),
+        }
+    }
+}
+
+/// A node in a parsed query.
+#[derive(Clone, Debug)]
+enum Expr {
+    Type(CardType),
+    Question(Regex),
+    Answer(Regex),
+    Not(Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+}
+impl Expr {
+    fn matches(&self, card: &Card) -> bool {
+        match self {
+            Self::Type(ty) => ty.matches(card),
+            // Errors here can only come from exceeding the backtracking limit, in which case we can't really say the
+            // card matches
+            Self::Question(re) => re.is_match(&card.question).unwrap_or(false),
+            Self::Answer(re) => re.is_match(&card.answer).unwrap_or(false),
+            Self::Not(expr) => !expr.matches(card),
+            Self::And(left, right) => left.matches(card) && right.matches(card),
+            Self::Or(left, right) => left.matches(card) || right.matches(card),
+        }
+    }
+}
+
+/// A single token in a query.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    OpenParen,
+    CloseParen,
+    /// A word, which may have been quoted (either a term or an operator).
+    Word {
+        text: String,
+        quoted: bool,
+    },
+}
+impl Token {
+    fn as_str(&self) -> &str {
+        match self {
+            Self::OpenParen => "(",
+            Self::CloseParen => ")",
+            Self::Word { text, .. } => text,
+        }
+    }
+    /// Determines whether or not this token is the given (unquoted) operator keyword.
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(self, Self::Word { text, quoted: false } if text.eq_ignore_ascii_case(keyword))
+    }
+}
+
+/// Splits the given query into tokens. Double quotes may be used anywhere in a word to include spaces and parentheses,
+/// and `\"` may be used inside them for a literal double quote.
+fn tokenize(query: &str) -> Result<Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut chars = query.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '(' => tokens.push(Token::OpenParen),
+            ')' => tokens.push(Token::CloseParen),
+            c if c.is_whitespace() => {}
+            c => {
+                let mut text = String::new();
+                let mut quoted = false;
+                let mut in_quotes = false;
+                let mut next = Some(c);
+                while let Some(c) = next {
+                    match c {
+                        '"' => {
+                            in_quotes = !in_quotes;
+                            quoted = true;
+                        }
+                        '\\' if in_quotes && chars.peek() == Some(&'"') => {
+                            text.push(chars.next().unwrap());
+                        }
+                        c => text.push(c),
+                    }
+                    // Stop at the end of the word, but never inside quotes
+                    next = match chars.peek() {
+                        Some(c) if !in_quotes && (c.is_whitespace() || *c == '(' || *c == ')') => {
+                            None
+                        }
+                        _ => chars.next(),
+                    };
+                }
+                if in_quotes {
+                    bail!("unterminated quotes in query");
+                }
+                tokens.push(Token::Word { text, quoted });
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// A simple recursive descent parser for queries.
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+impl Parser {
+    fn parse_or(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_and()?;
+        while self.eat_keyword("or") {
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+    fn parse_and(&mut self) -> Result<Expr> {
+        let mut expr = self.parse_not()?;
+        while self.eat_keyword("and") {
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_not()?));
+        }
+        Ok(expr)
+    }
+    fn parse_not(&mut self) -> Result<Expr> {
+        if self.eat_keyword("not") {
+            Ok(Expr::Not(Box::new(self.parse_not()?)))
+        } else {
+            self.parse_atom()
+        }
+    }
+    fn parse_atom(&mut self) -> Result<Expr> {
+        let token = self
+            .tokens
+            .get(self.pos)
+            .cloned()
+            .ok_or_else(|| anyhow!("unexpected end of query"))?;
+        self.pos += 1;
+        match token {
+            Token::OpenParen => {
+                let expr = self.parse_or()?;
+                if self.tokens.get(self.pos) != Some(&Token::CloseParen) {
+                    bail!("unclosed parenthesis in query");
+                }
+                self.pos += 1;
+                Ok(expr)
+            }
+            Token::CloseParen => bail!("unexpected ')' in query"),
+            Token::Word { text, quoted } => {
+                if let Some(regex) = text.strip_prefix("question:") {
+                    Ok(Expr::Question(parse_regex(regex)?))
+                } else if let Some(regex) = text.strip_prefix("answer:") {
+                    Ok(Expr::Answer(parse_regex(regex)?))
+                } else if !quoted
+                    && ["and", "or", "not"]
+                        .iter()
+                        .any(|keyword| text.eq_ignore_ascii_case(keyword))
+                {
+                    bail!("expected a term in query, found '{text}'")
+                } else {
+                    Ok(Expr::Type(text.parse::<CardType>().with_context(|| {
+                        format!("invalid term '{text}' in query")
+                    })?))
+                }
+            }
+        }
+    }
+    /// Consumes the next token if it is the given keyword, returning whether or not it was.
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        if self
+            .tokens
+            .get(self.pos)
+            .is_some_and(|token| token.is_keyword(keyword))
+        {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+fn parse_regex(regex: &str) -> Result<Regex> {
+    Regex::new(regex).with_context(|| format!("invalid regular expression '{regex}' in query"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::set::tests::card;
+
+    fn word(text: &str, quoted: bool) -> Token {
+        Token::Word {
+            text: text.to_string(),
+            quoted,
+        }
+    }
+
+    #[test]
+    fn words_and_parentheses_are_tokenized() {
+        assert_eq!(
+            tokenize("(starred or tag:verbs)and not difficult").unwrap(),
+            vec![
+                Token::OpenParen,
+                word("starred", false),
+                word("or", false),
+                word("tag:verbs", false),
+                Token::CloseParen,
+                word("and", false),
+                word("not", false),
+                word("difficult", false),
+            ]
+        );
+    }
+
+    #[test]
+    fn quotes_can_contain_spaces_and_parentheses() {
+        assert_eq!(
+            tokenize(r#"question:"^to (be) " "and""#).unwrap(),
+            vec![word("question:^to (be) ", true), word("and", true)]
+        );
+        assert_eq!(
+            tokenize(r#"answer:"say \"hi\"""#).unwrap(),
+            vec![word(r#"answer:say "hi""#, true)]
+        );
+    }
+
+    #[test]
+    fn unterminated_quotes_are_rejected() {
+        assert!(tokenize(r#"question:"to be"#).is_err());
+        assert!(Query::parse(r#"starred and answer:"x\""#).is_err());
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and_which_binds_tighter_than_or() {
+        // This should be `starred or (tag:verbs and (not difficult))`
+        let query = Query::parse("starred or tag:verbs and not difficult").unwrap();
+
+        let mut starred_difficult = card("Q", "A");
+        starred_difficult.starred = true;
+        starred_difficult.difficult = true;
+        assert!(query.matches(&starred_difficult));
+
+        let mut verb = card("Q", "A");
+        verb.tags = vec!["verbs".to_string()];
+        assert!(query.matches(&verb));
+
+        verb.difficult = true;
+        assert!(!query.matches(&verb));
+    }
+
+    #[test]
+    fn parentheses_override_precedence() {
+        let query = Query::parse("(starred or tag:verbs) and not difficult").unwrap();
+
+        let mut starred_difficult = card("Q", "A");
+        starred_difficult.starred = true;
+        starred_difficult.difficult = true;
+        assert!(!query.matches(&starred_difficult));
+    }
+
+    #[test]
+    fn keywords_are_case_insensitive_unless_quoted() {
+        let query = Query::parse(r#"NOT starred AND question:"^to ""#).unwrap();
+        assert!(query.matches(&card("to be", "être")));
+        assert!(!query.matches(&card("be", "être")));
+
+        assert!(Query::parse(r#"starred "and" difficult"#).is_err());
+    }
+
+    #[test]
+    fn invalid_queries_are_rejected() {
+        for query in [
+            "",
+            "starred and",
+            "(starred or difficult",
+            "starred)",
+            "and starred",
+            "starred difficult",
+            "weird",
+            "question:(",
+        ] {
+            assert!(Query::parse(query).is_err(), "'{query}' should be invalid");
+        }
+    }
+}
@@ -1,17 +1,44 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    str::FromStr,
+};
 
-use anyhow::Result;
+use crate::methods::Method;
+use anyhow::{anyhow, bail, Result};
 use rhai::Dynamic;
 use serde::{Deserialize, Serialize};
 use uuid::Uuid;
 
 /// A single key-value pair that represents an element in the set.
-#[derive(Serialize, Deserialize)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct Card {
     /// The prompt the user will be given for this card.
     pub question: String,
     /// The answer this card has (which will be shown to the user).
     pub answer: String,
+    /// A stable identifier given to this card by the adapter that created it, if there was one. When a set is
+    /// updated, this is used to match cards in the source with cards in the set, meaning questions can be changed
+    /// without losing progress.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_id: Option<String>,
+    /// Any tags that have been attached to this card, which can be used to group cards together.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    /// A hint for this card, which callers may choose to show to users who are struggling to remember the answer.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+    /// The line in the source that this card came from, if the adapter provided it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_line: Option<usize>,
+    /// Any other properties the adapter provided for this card. These are not used by Forne at all, but are kept
+    /// so that callers can display them however they like.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub extra: BTreeMap<String, Dynamic>,
+    /// The position of this card among all the cards the adapter produced the last time the set was updated from its
+    /// source. This is used to keep cards in the same order as the source when saving sets with [`CardOrder::Source`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source_position: Option<usize>,
     /// Whether or not this card has been seen yet in the active test.
     pub seen_in_test: bool,
     /// Whether or not this card has been marked as difficult. Difficult cards are intended to
@@ -22,10 +49,62 @@ pub struct Card {
     /// them wrong in a test, and they will be unstarred if the user later gets them right in a test. This
     /// behaviour can be customised with flags.
     pub starred: bool,
-    /// Data about this card stored by the current method. This can be serialized and deserialized, but
-    /// is completely arbitrary, and different cards may store completely different data here. This should
-    /// be passed to and from method scripts with no intervention from Rust.
-    pub method_data: Dynamic,
+    /// Data about this card stored by each method that has been used to learn it, keyed by method name. This can be
+    /// serialized and deserialized, but is completely arbitrary, and different cards may store completely different
+    /// data here. This should be passed to and from method scripts with no intervention from Rust.
+    ///
+    /// Keeping the data of every method separately means the user can switch between methods without losing their
+    /// progress in any of them.
+    pub method_states: BTreeMap<String, Dynamic>,
+    /// Every response the user has ever given to this card, in the order they were given.
+    #[serde(default)]
+    pub history: Vec<Review>,
+}
+impl Card {
+    /// Creates a slim representation of this card, which avoids potentially expensive cloning of the `Dynamic`
+    /// data the method maintains about it.
+    pub(crate) fn to_slim(&self) -> SlimCard {
+        SlimCard {
+            question: self.question.clone(),
+            answer: self.answer.clone(),
+            tags: self.tags.clone(),
+            hint: self.hint.clone(),
+            source_line: self.source_line,
+            extra: self.extra.clone(),
+            difficult: self.difficult,
+            starred: self.starred,
+        }
+    }
+}
+
+/// A record of a single response the user gave to a card.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Review {
+    /// The time at which the response was given, in seconds since Unix epoch.
+    pub timestamp: i64,
+    /// Whether the card was reviewed in a learn session or a test.
+    pub mode: ReviewMode,
+    /// The name of the learning method used, if this was a learn session.
+    pub method: Option<String>,
+    /// The response the user gave, which will be one of the responses allowed by the method (or `y`/`n` in a test).
+    pub response: String,
+    /// How long the user took to respond to the card, in milliseconds, if the caller measured it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency: Option<u64>,
+    /// A unique identifier for this review, which tells it apart from identical responses given in the same second
+    /// (e.g. on another device) when sets are merged. Reviews recorded by older versions of Forne won't have one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+}
+
+/// The different ways a card can be reviewed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ReviewMode {
+    /// A learn session, driven by a learning method.
+    Learn,
+    /// A test.
+    Test,
 }
 
 /// A slim representation of a card without internal metadata, which will be returned when polling a
@@ -36,6 +115,14 @@ pub struct SlimCard {
     pub question: String,
     /// The answer on the 'other side' of the card.
     pub answer: String,
+    /// The tags attached to the card.
+    pub tags: Vec<String>,
+    /// A hint for the card, if it has one.
+    pub hint: Option<String>,
+    /// The line in the source the card came from, if the adapter provided it.
+    pub source_line: Option<usize>,
+    /// Any extra properties the adapter provided for the card.
+    pub extra: BTreeMap<String, Dynamic>,
     /// Whether or not the card has been automatically marked as difficult. Callers may wish to highlight this
     /// to users when a question is displayed, or not.
     pub difficult: bool,
@@ -45,8 +132,10 @@ pub struct SlimCard {
 }
 
 /// The different card categories that operations on sets can be classed into.
-#[derive(Clone, Copy, Debug, PartialEq, Eq)]
-#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+///
+/// This can be parsed from a string, which should be one of `all`, `difficult`, or `starred`, or `tag:` followed by a
+/// comma-separated list of tags (e.g. `tag:verbs,bio::cells`).
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum CardType {
     /// All the cards in the set.
     All,
@@ -54,18 +143,181 @@ pub enum CardType {
     Difficult,
     /// Only cards that have been automatically starred when the user got them wrong in a test.
     Starred,
+    /// Only cards that have at least one of the given tags. Tags are hierarchical, with levels separated by `::`, so
+    /// targeting `bio` will include cards tagged `bio::cells`, but targeting `bio::cells` will not include cards tagged
+    /// only `bio`.
+    Tagged(Vec<String>),
+}
+impl CardType {
+    /// Determines whether or not the given card is of this type.
+    pub(crate) fn matches(&self, card: &Card) -> bool {
+        match self {
+            Self::All => true,
+            Self::Difficult => card.difficult,
+            Self::Starred => card.starred,
+            Self::Tagged(targets) => targets.iter().any(|target| {
+                card.tags.iter().any(|tag| {
+                    tag == target
+                        || tag
+                            .strip_prefix(target.as_str())
+                            .is_some_and(|rest| rest.starts_with("::"))
+                })
+            }),
+        }
+    }
+}
+impl FromStr for CardType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "all" => Ok(Self::All),
+            "difficult" => Ok(Self::Difficult),
+            "starred" => Ok(Self::Starred),
+            _ => {
+                if let Some(tags) = s.strip_prefix("tag:") {
+                    let tags = tags
+                        .split(',')
+                        .map(|tag| tag.trim().to_string())
+                        .filter(|tag| !tag.is_empty())
+                        .collect::<Vec<_>>();
+                    if tags.is_empty() {
+                        bail!("no tags given to target");
+                    }
+                    Ok(Self::Tagged(tags))
+                } else {
+                    bail!("invalid card type '{s}' (expected `all`, `difficult`, `starred`, or `tag:<tags>`)")
+                }
+            }
+        }
+    }
+}
+
+/// The version of the layout sets are currently serialized in. Any change to [`Set`] or [`Card`] that would prevent
+/// previously serialized sets from being deserialized should increment this, and add a migration from the previous
+/// version to the list of migrations in this module, so older sets are upgraded when they're loaded.
+pub const SET_VERSION: u32 = 1;
+
+/// Migrations that upgrade the JSON of a serialized set from one layout version to the next. The migration at index `n`
+/// upgrades a set from version `n` to version `n + 1`. Sets serialized before versions were recorded are version 0.
+const MIGRATIONS: [fn(&mut serde_json::Value); SET_VERSION as usize] =
+    [upgrade_single_method_layout];
+
+/// Options that control how a set is serialized.
+///
+/// By default, sets are serialized as compactly and quickly as possible, with cards in an arbitrary order that will
+/// change every time the set is saved. If the set will be tracked in version control, pretty-printing it with cards in
+/// a stable order will produce much more readable diffs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SaveOptions {
+    /// Whether or not to pretty-print the set, with each property on its own line.
+    pub pretty: bool,
+    /// The order cards should be written in, or `None` for an arbitrary order.
+    pub order: Option<CardOrder>,
+}
+
+/// The orders in which cards can be written when a set is saved.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "cli", derive(clap::ValueEnum))]
+#[serde(rename_all = "lowercase")]
+pub enum CardOrder {
+    /// Order cards by their unique identifiers.
+    Id,
+    /// Order cards by their position in the source the set was last updated from. Cards that have never been updated
+    /// from a source (or which are no longer in it) come last, ordered by their unique identifiers.
+    Source,
+}
+
+/// Where a set's content and methods come from. Recording these in the set means it can be updated from its source, or
+/// learned with a custom method, without the caller having to provide them every time.
+///
+/// Forne itself never reads any of these files: it's up to the caller to decide what to do with them. The CLI records
+/// paths relative to the set file, so sets can be moved around (or shared in a repository) along with their sources.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct SetSources {
+    /// The path to the source file the set is created from.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub source: Option<PathBuf>,
+    /// The adapter used to turn the source into cards.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub adapter: Option<AdapterSource>,
+    /// The paths to the scripts of any custom methods that have been used with the set, keyed by method name.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub methods: BTreeMap<String, PathBuf>,
+}
+
+/// The ways an adapter can be recorded in a set.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AdapterSource {
+    /// The path to the adapter script.
+    Path(PathBuf),
+    /// The full body of the adapter script, embedded in the set. This is useful for sharing a set along with its source,
+    /// but it means changes to the original script won't be picked up.
+    Embedded(String),
+}
+
+/// A record of exactly which script a custom method was defined by when it was last used with a set, which is used to detect
+/// when a method's script has been changed (or replaced with a different script of the same name), since the new script
+/// might not understand the data the old one stored.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MethodFingerprint {
+    /// The SHA-256 hash of the method's script, in hexadecimal.
+    pub hash: String,
+    /// The version the script declares in its `const VERSION`, if it has one. If two different scripts declare the same
+    /// version, the method's author has promised they store their data in the same way, so one can be used in place of the
+    /// other.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+impl MethodFingerprint {
+    /// Determines whether or not the method this is a fingerprint of can be used with data stored by the method with the
+    /// given fingerprint.
+    pub fn is_compatible_with(&self, other: &Self) -> bool {
+        self.hash == other.hash || (self.version.is_some() && self.version == other.version)
+    }
 }
 
 /// A set of cards with associated data about how learning this set has progressed.
 #[derive(Serialize, Deserialize)]
 pub struct Set {
-    /// The name of the method used on this set. As methods provide their own custom metadata for each card, it
-    /// is not generally possible to transition a set from one learning method to another while keeping your
-    /// progress, unless a transformer is provided by the methods to do so. This acts as a guard to prevent
-    /// the user from accidentally deleting all their hard work!
+    /// The version of the layout this set was serialized in. This is used to upgrade sets created by older versions of
+    /// Forne when they're loaded, and should not be changed manually.
+    pub version: u32,
+    /// The name of the method most recently used to learn this set (or the one it was created with, if it hasn't been
+    /// learned yet). As methods provide their own custom metadata for each card, and this is stored separately for
+    /// each method, sets can be learned with several methods without any of them interfering with each other.
     pub method: String,
     /// A list of all the cards in the set.
     pub cards: HashMap<Uuid, Card>,
+    /// Cards that have been removed from the source this set was created from, but which have been kept with all
+    /// their progress, rather than being deleted. These will never be reviewed or listed.
+    #[serde(default)]
+    pub archived: HashMap<Uuid, Card>,
+    /// How this set should be serialized whenever it is saved.
+    #[serde(default)]
+    pub save_options: SaveOptions,
+    /// Where this set's content and methods come from.
+    #[serde(default)]
+    pub sources: SetSources,
+    /// When this set was last created or updated from its source, in seconds since Unix epoch. This is used to work out
+    /// which copy of a set has the most recent content when merging. Sets last updated by older versions of Forne won't
+    /// have this.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_updated: Option<i64>,
+    /// The date of the exam the user is studying this set for, if they've set one, in seconds since Unix epoch. Methods
+    /// designed for cramming can use this (through `get_exam_date()`) to make sure every card is reviewed enough times
+    /// before the exam.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exam_date: Option<i64>,
+    /// Any parameters the user has changed from the defaults of the methods used on this set, keyed by method name, and
+    /// then by parameter name. Parameters that aren't in here will take the defaults their methods declare.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub method_parameters: BTreeMap<String, BTreeMap<String, Dynamic>>,
+    /// Fingerprints of the scripts of the custom methods used with this set when they were last used, keyed by method name.
+    /// Inbuilt methods are not recorded here, as they're only ever changed along with Forne itself.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub method_fingerprints: BTreeMap<String, MethodFingerprint>,
     /// The state of the set in terms of tests. This will be `Some(..)` if there was a previous
     /// test, and the attached string will be the name of the method used. Runs on different targets
     /// will not interfere with each other, and this program is built to support them.
@@ -82,21 +334,153 @@ pub struct Set {
     pub test_in_progress: bool,
 }
 impl Set {
-    /// Saves this set to the given JSON file, preserving all progress.
+    /// Saves this set to the given JSON file, preserving all progress. This will use the set's [`SaveOptions`].
     pub fn save(&self) -> Result<String> {
-        let json = serde_json::to_string(&self)?;
+        self.save_with(self.save_options)
+    }
+    /// Saves this set to JSON using the given options, rather than those stored in the set. To make these options persist
+    /// for future saves, set [`Self::save_options`] instead.
+    pub fn save_with(&self, options: SaveOptions) -> Result<String> {
+        // Avoid the overhead of going through a JSON value if we can
+        if options == SaveOptions::default() {
+            return Ok(serde_json::to_string(&self)?);
+        }
+
+        // Serializing to a value preserves the order of everything except the cards, which we can then sort (all other
+        // maps in the set are already ordered)
+        let mut value = serde_json::to_value(self)?;
+        if let Some(order) = options.order {
+            for (key, cards) in [("cards", &self.cards), ("archived", &self.archived)] {
+                let mut ids = cards.iter().collect::<Vec<_>>();
+                match order {
+                    CardOrder::Id => ids.sort_by_key(|(id, _)| **id),
+                    CardOrder::Source => ids.sort_by_key(|(id, card)| {
+                        (card.source_position.is_none(), card.source_position, **id)
+                    }),
+                }
+
+                let old_cards = value[key].as_object_mut().unwrap();
+                let mut new_cards = serde_json::Map::new();
+                for (id, _) in ids {
+                    let id = id.to_string();
+                    let card = old_cards.remove(&id).unwrap();
+                    new_cards.insert(id, card);
+                }
+                value[key] = serde_json::Value::Object(new_cards);
+            }
+        }
+
+        let json = if options.pretty {
+            serde_json::to_string_pretty(&value)?
+        } else {
+            serde_json::to_string(&value)?
+        };
         Ok(json)
     }
-    /// Loads this set from the given JSON.
+    /// Loads this set from the given JSON. Sets serialized by older versions of Forne will be automatically upgraded to
+    /// the current layout.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the set was serialized by a newer version of Forne, which this version cannot
+    /// understand.
     pub fn from_json(json: &str) -> Result<Self> {
-        let set = serde_json::from_str(json)?;
+        let value = serde_json::from_str(json)?;
+        Self::from_value(value)
+    }
+    /// Loads this set from the given generic representation of its serialized form, upgrading it to the current layout if
+    /// necessary. This allows sets to be deserialized from any self-describing format in the same way as from JSON.
+    pub(crate) fn from_value(mut value: serde_json::Value) -> Result<Self> {
+        migrate(&mut value)?;
+        let set = serde_json::from_value(value)?;
         Ok(set)
     }
-    /// Resets all cards in a learn back to the default metadata values prescribed by the learning method.
-    pub(crate) fn reset_learn(&mut self, default_data: Dynamic) {
+    /// Resets all cards in a learn with the given method back to the default metadata values prescribed by that method.
+    /// The data of other methods will not be affected.
+    pub(crate) fn reset_learn(&mut self, method: &Method) -> Result<()> {
+        let default_data = (method.get_default_metadata)()?;
+        for card in self.cards.values_mut() {
+            card.method_states
+                .insert(method.name.clone(), default_data.clone());
+        }
+        // The cards no longer have any data from an old version of the method's script
+        self.record_method(method);
+
+        Ok(())
+    }
+    /// Checks that the script of the given method is compatible with the one that was used when it was last used with this
+    /// set (see [`MethodFingerprint`]), and records it as the one used from now on if so. Inbuilt methods, and custom
+    /// methods which haven't been used with this set before, will always pass this check.
+    ///
+    /// # Errors
+    ///
+    /// This will return an error if the method's script has changed in a way that may not be compatible with the data it
+    /// stored before. In that case, the caller should either migrate the set's progress to the new script (see
+    /// [`Self::migrate_method`]), or, if they're sure the data is still compatible, record the new script with
+    /// [`Self::record_method`].
+    pub(crate) fn check_method(&mut self, method: &Method) -> Result<()> {
+        if let (Some(fingerprint), Some(recorded)) = (
+            &method.fingerprint,
+            self.method_fingerprints.get(&method.name),
+        ) {
+            if !fingerprint.is_compatible_with(recorded) {
+                let versions = match (&recorded.version, &fingerprint.version) {
+                    (Some(old), Some(new)) => format!(" (from version {old} to version {new})"),
+                    _ => String::new(),
+                };
+                bail!("the script for custom method '{}' has changed since it was last used with this set{versions}, and its progress may not be compatible with the new script; either migrate the progress to the new script, or, if you're sure the changes don't affect the method's data, accept them", method.name);
+            }
+        }
+
+        self.record_method(method);
+        Ok(())
+    }
+    /// Records the script of the given method as the one most recently used with this set, if it's a custom method.
+    pub(crate) fn record_method(&mut self, method: &Method) {
+        if let Some(fingerprint) = &method.fingerprint {
+            self.method_fingerprints
+                .insert(method.name.clone(), fingerprint.clone());
+        }
+    }
+    /// Converts every card's data for the method `from` into data for the given method using its transformer, overwriting
+    /// any data the cards previously had for the given method. Cards with no data for `from`, or which the transformer
+    /// cannot handle, will be given the method's default metadata. The data for `from` will be kept.
+    ///
+    /// This returns the number of cards that were transformed.
+    pub(crate) fn migrate_method(&mut self, from: &str, method: &Method) -> Result<usize> {
+        if method.transform_from.is_none() {
+            bail!(
+                "method '{}' does not define a transformer (`transform_from`), so progress cannot be migrated to it",
+                method.name
+            );
+        }
+        // Progress can only be migrated from a method to itself if its script has changed, in which case the transformer
+        // will be given the data the old script stored
+        let script_changed = match (&method.fingerprint, self.method_fingerprints.get(from)) {
+            (Some(fingerprint), Some(recorded)) => !fingerprint.is_compatible_with(recorded),
+            _ => false,
+        };
+        if from == method.name && !script_changed {
+            bail!(
+                "cannot migrate progress from a method to itself (unless its script has changed)"
+            );
+        }
+
+        let mut num_transformed = 0;
         for card in self.cards.values_mut() {
-            card.method_data = default_data.clone();
+            let method_data = match card.method_states.get(from) {
+                Some(old_data) => {
+                    num_transformed += 1;
+                    method.initial_metadata(Some((from, old_data)), card.difficult)?
+                }
+                None => (method.get_default_metadata)()?,
+            };
+            card.method_states.insert(method.name.clone(), method_data);
         }
+        self.method = method.name.clone();
+        self.record_method(method);
+
+        Ok(num_transformed)
     }
     /// Resets all test progress for this set. This is irreversible!
     ///
@@ -113,3 +497,282 @@ impl Set {
         }
     }
 }
+
+/// Upgrades the given JSON of a serialized set to the current layout version, one version at a time.
+fn migrate(value: &mut serde_json::Value) -> Result<()> {
+    // Sets from before versioning have no version at all
+    let version = match value.get("version") {
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| anyhow!("set has invalid version"))?,
+        None => 0,
+    };
+    if version > SET_VERSION {
+        bail!("this set was created by a newer version of forne (set version is {version}, but this version of forne only supports up to {SET_VERSION}), please upgrade forne to use it");
+    }
+
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(value);
+    }
+    if let Some(set) = value.as_object_mut() {
+        set.insert("version".to_string(), SET_VERSION.into());
+    }
+
+    Ok(())
+}
+
+/// Upgrades the JSON of a set from version 0 to version 1. Version 0 sets had a single `method_data` property on each card,
+/// belonging to the set's `method`, while version 1 sets store data for several methods.
+fn upgrade_single_method_layout(value: &mut serde_json::Value) {
+    let method = match value.get("method").and_then(|method| method.as_str()) {
+        Some(method) => method.to_string(),
+        None => return,
+    };
+    for key in ["cards", "archived"] {
+        if let Some(cards) = value.get_mut(key).and_then(|cards| cards.as_object_mut()) {
+            for card in cards.values_mut().filter_map(|card| card.as_object_mut()) {
+                if let Some(method_data) = card.remove("method_data") {
+                    let mut method_states = serde_json::Map::new();
+                    method_states.insert(method.clone(), method_data);
+                    card.insert(
+                        "method_states".to_string(),
+                        serde_json::Value::Object(method_states),
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use serde_json::json;
+
+    /// Creates a card with the given question and answer, and no progress, for use in tests.
+    pub(crate) fn card(question: &str, answer: &str) -> Card {
+        Card {
+            question: question.to_string(),
+            answer: answer.to_string(),
+            source_id: None,
+            tags: Vec::new(),
+            hint: None,
+            source_line: None,
+            extra: BTreeMap::new(),
+            source_position: None,
+            seen_in_test: false,
+            difficult: false,
+            starred: false,
+            method_states: BTreeMap::new(),
+            history: Vec::new(),
+        }
+    }
+
+    /// Creates a set with the given cards, and no progress, for use in tests.
+    pub(crate) fn set(cards: impl IntoIterator<Item = (Uuid, Card)>) -> Set {
+        Set {
+            version: SET_VERSION,
+            method: "speed".to_string(),
+            cards: cards.into_iter().collect(),
+            archived: HashMap::new(),
+            save_options: SaveOptions::default(),
+            sources: SetSources::default(),
+            last_updated: None,
+            exam_date: None,
+            method_parameters: BTreeMap::new(),
+            method_fingerprints: BTreeMap::new(),
+            run_state: None,
+            test_in_progress: false,
+        }
+    }
+
+    const ID: &str = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+
+    #[test]
+    fn v0_sets_are_upgraded() {
+        let json = json!({
+            "method": "speed",
+            "cards": {
+                ID: {
+                    "question": "Q",
+                    "answer": "A",
+                    "seen_in_test": false,
+                    "difficult": true,
+                    "starred": false,
+                    "method_data": { "weight": 1.5 },
+                },
+            },
+            "run_state": null,
+            "test_in_progress": false,
+        });
+        let set = Set::from_json(&json.to_string()).unwrap();
+
+        assert_eq!(set.version, SET_VERSION);
+        let card = &set.cards[&Uuid::parse_str(ID).unwrap()];
+        assert!(card.difficult);
+        assert_eq!(card.method_states.len(), 1);
+        assert_eq!(
+            serde_json::to_value(&card.method_states["speed"]).unwrap(),
+            json!({ "weight": 1.5 })
+        );
+    }
+
+    #[test]
+    fn empty_v0_sets_are_upgraded() {
+        let json = json!({
+            "method": "speed",
+            "cards": {},
+            "run_state": null,
+            "test_in_progress": false,
+        });
+        let set = Set::from_json(&json.to_string()).unwrap();
+
+        assert_eq!(set.version, SET_VERSION);
+        assert!(set.cards.is_empty());
+        assert!(set.archived.is_empty());
+    }
+
+    #[test]
+    fn current_sets_are_not_migrated() {
+        let json = json!({
+            "version": SET_VERSION,
+            "method": "speed",
+            "cards": {
+                ID: {
+                    "question": "Q",
+                    "answer": "A",
+                    "seen_in_test": false,
+                    "difficult": false,
+                    "starred": false,
+                    "method_states": { "speed": { "weight": 1.5 } },
+                },
+            },
+            "run_state": null,
+            "test_in_progress": false,
+        });
+        let set = Set::from_json(&json.to_string()).unwrap();
+
+        let card = &set.cards[&Uuid::parse_str(ID).unwrap()];
+        assert_eq!(
+            serde_json::to_value(&card.method_states).unwrap(),
+            json!({ "speed": { "weight": 1.5 } })
+        );
+    }
+
+    #[test]
+    fn newer_sets_are_rejected() {
+        let json = json!({
+            "version": SET_VERSION + 1,
+            "method": "speed",
+            "cards": {},
+            "run_state": null,
+            "test_in_progress": false,
+        });
+        let err = Set::from_json(&json.to_string()).err().unwrap();
+
+        assert!(err.to_string().contains("newer version of forne"));
+    }
+
+    #[test]
+    fn invalid_versions_are_rejected() {
+        let json = json!({
+            "version": "one",
+            "method": "speed",
+            "cards": {},
+            "run_state": null,
+            "test_in_progress": false,
+        });
+
+        assert!(Set::from_json(&json.to_string()).is_err());
+    }
+
+    /// Gets the identifiers of the cards in the given serialized set, in the order they were written.
+    fn card_order(json: &str) -> Vec<String> {
+        let value: serde_json::Value = serde_json::from_str(json).unwrap();
+        value["cards"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn cards_are_saved_in_order() {
+        let ids = [Uuid::new_v4(), Uuid::new_v4(), Uuid::new_v4()];
+        // The last card has never been updated from a source
+        let positions = [Some(1), Some(0), None];
+        let set = set(ids.into_iter().zip(positions).map(|(id, position)| {
+            let mut card = card("Q", "A");
+            card.source_position = position;
+            (id, card)
+        }));
+
+        let by_id = SaveOptions {
+            pretty: false,
+            order: Some(CardOrder::Id),
+        };
+        let mut sorted_ids = ids;
+        sorted_ids.sort();
+        assert_eq!(
+            card_order(&set.save_with(by_id).unwrap()),
+            sorted_ids.map(|id| id.to_string())
+        );
+
+        let by_source = SaveOptions {
+            pretty: true,
+            order: Some(CardOrder::Source),
+        };
+        let json = set.save_with(by_source).unwrap();
+        assert_eq!(
+            card_order(&json),
+            [ids[1], ids[0], ids[2]].map(|id| id.to_string())
+        );
+        assert_eq!(json, set.save_with(by_source).unwrap());
+    }
+
+    /// Compiles a custom method called `me/speed` from the given script.
+    fn custom_method<'e>(script: &str, engine: &'e rhai::Engine) -> Method<'e> {
+        crate::RawMethod::Custom {
+            name: "me/speed".to_string(),
+            body: script.to_string(),
+        }
+        .into_method(None, engine)
+        .unwrap()
+    }
+
+    #[test]
+    fn changed_custom_methods_are_detected() {
+        let engine = crate::Forne::create_engine();
+        let script = include_str!("methods/speed-v1.rhai");
+        let changed = format!("{script}\n// A change");
+        let mut unversioned = set([]);
+
+        unversioned
+            .check_method(&custom_method(script, &engine))
+            .unwrap();
+        unversioned
+            .check_method(&custom_method(script, &engine))
+            .unwrap();
+        assert!(unversioned
+            .check_method(&custom_method(&changed, &engine))
+            .is_err());
+
+        // Scripts that declare the same version are assumed to store the same data
+        let script = format!("const VERSION = \"1\";\n{script}");
+        let changed = format!("{script}\n// A change");
+        let mut versioned = set([]);
+
+        versioned
+            .check_method(&custom_method(&script, &engine))
+            .unwrap();
+        versioned
+            .check_method(&custom_method(&changed, &engine))
+            .unwrap();
+        assert_eq!(
+            versioned.method_fingerprints["me/speed"],
+            custom_method(&changed, &engine).fingerprint.unwrap()
+        );
+    }
+}